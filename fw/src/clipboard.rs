@@ -0,0 +1,150 @@
+use crate::sequencer::{
+    mark_dirty, mark_dirty_cells, mark_dirty_steps, Pattern, SequencerState, Step, Track,
+    DIRTY_NOTE_DATA, DIRTY_PATTERN, DIRTY_RT_CACHE, MAX_STEPS,
+};
+use crate::utils::iter_bits_u8;
+
+// Lives in the sequencer state, so sizing it for the largest variant is the point.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy)]
+pub enum Clipboard {
+    Empty,
+    Steps { steps: [Step; MAX_STEPS], len: u8 },
+    Track(Track),
+    Pattern(Pattern),
+}
+
+impl Clipboard {
+    pub const fn new() -> Self {
+        Clipboard::Empty
+    }
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copies steps `start..=end` of one track. The range is cut at the track length.
+pub fn copy_steps(
+    sequencer_state: &mut SequencerState,
+    pattern_index: u8,
+    track_index: u8,
+    start: u8,
+    end: u8,
+) {
+    let track = &sequencer_state.patterns[pattern_index as usize].tracks[track_index as usize];
+    let end = end.min(track.length.saturating_sub(1));
+    if start > end {
+        return;
+    }
+    let mut steps = [Step::new(); MAX_STEPS];
+    let len = end - start + 1;
+    steps[..len as usize].copy_from_slice(&track.steps[start as usize..=end as usize]);
+    sequencer_state.clipboard = Clipboard::Steps { steps, len };
+}
+
+pub fn copy_track(sequencer_state: &mut SequencerState, pattern_index: u8, track_index: u8) {
+    let track = sequencer_state.patterns[pattern_index as usize].tracks[track_index as usize];
+    sequencer_state.clipboard = Clipboard::Track(track);
+}
+
+pub fn copy_pattern(sequencer_state: &mut SequencerState, pattern_index: u8) {
+    let pattern = sequencer_state.patterns[pattern_index as usize];
+    sequencer_state.clipboard = Clipboard::Pattern(pattern);
+}
+
+/// Pastes into `tracks` of pattern `pattern_index`. Steps go in from `start` and stop at each
+/// track's length, a track replaces each target track, and a pattern replaces the whole pattern
+/// regardless of `tracks`. Returns false when the clipboard is empty.
+pub fn paste(
+    sequencer_state: &mut SequencerState,
+    pattern_index: u8,
+    tracks: u8,
+    start: u8,
+) -> bool {
+    let clipboard = sequencer_state.clipboard;
+    let pattern = &mut sequencer_state.patterns[pattern_index as usize];
+    match clipboard {
+        Clipboard::Empty => return false,
+        Clipboard::Steps { steps, len } => {
+            for track_index in iter_bits_u8(tracks) {
+                let track = &mut pattern.tracks[track_index as usize];
+                let end = (start + len).min(track.length);
+                for index in start..end {
+                    track.steps[index as usize] = steps[(index - start) as usize];
+                    mark_dirty_cells(1 << track_index, 1 << index);
+                }
+            }
+        }
+        Clipboard::Track(source) => {
+            for track_index in iter_bits_u8(tracks) {
+                pattern.tracks[track_index as usize] = source;
+            }
+            mark_dirty_cells(tracks, u16::MAX);
+            mark_dirty(DIRTY_PATTERN);
+        }
+        Clipboard::Pattern(source) => {
+            *pattern = source;
+            mark_dirty_steps(u16::MAX);
+            mark_dirty(DIRTY_PATTERN);
+        }
+    }
+    mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::undo::{record_edit, undo};
+
+    fn with_pitches(state: &mut SequencerState, pattern: usize, track: usize) {
+        for (index, step) in state.patterns[pattern].tracks[track].steps.iter_mut().enumerate() {
+            step.pitch = 40 + index as u8;
+        }
+    }
+
+    #[test]
+    fn steps_paste_stops_at_the_target_length() {
+        let mut state = SequencerState::new();
+        with_pitches(&mut state, 0, 0);
+        copy_steps(&mut state, 0, 0, 2, 9);
+        state.patterns[0].tracks[1].length = 8;
+        assert!(paste(&mut state, 0, 0b10, 5));
+        let pitches = state.patterns[0].tracks[1].steps.map(|step| step.pitch);
+        assert_eq!(&pitches[..10], &[0, 0, 0, 0, 0, 42, 43, 44, 0, 0]);
+    }
+
+    #[test]
+    fn track_paste_across_patterns_takes_the_settings() {
+        let mut state = SequencerState::new();
+        with_pitches(&mut state, 0, 1);
+        state.patterns[0].tracks[1].length = 12;
+        state.patterns[0].tracks[1].offset = 3;
+        copy_track(&mut state, 0, 1);
+
+        let before = state.patterns[2];
+        assert!(paste(&mut state, 2, 0b1010_0000, 0));
+        record_edit(&mut state, 2, &before);
+        for track in [5, 7] {
+            let pasted = &state.patterns[2].tracks[track];
+            assert_eq!((pasted.length, pasted.offset, pasted.steps[11].pitch), (12, 3, 51));
+        }
+        assert_eq!(state.patterns[2].tracks[6].length, MAX_STEPS as u8);
+
+        assert!(undo(&mut state));
+        let restored = &state.patterns[2].tracks[5];
+        assert_eq!((restored.length, restored.offset, restored.steps[11].pitch), (16, 0, 0));
+    }
+
+    #[test]
+    fn empty_clipboard_pastes_nothing() {
+        let mut state = SequencerState::new();
+        with_pitches(&mut state, 0, 0);
+        assert!(!paste(&mut state, 0, 0xFF, 0));
+        assert_eq!(state.patterns[0].tracks[0].steps[3].pitch, 43);
+        assert_eq!(state.patterns[0].tracks[1].steps[3].pitch, 0);
+    }
+}
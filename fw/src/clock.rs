@@ -0,0 +1,251 @@
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use stm32f4xx_hal::pac::{self, TIM5};
+use stm32f4xx_hal::{interrupt, rcc::Clocks};
+
+use crate::sequencer::{
+    external_clock_lost, external_clock_step, external_clock_tempo, external_reset,
+};
+
+pub static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Internal as u8);
+pub static CLOCK_IN_PPQN: AtomicU32 = AtomicU32::new(4);
+pub const CLOCK_IN_PPQN_OPTIONS: [u32; 4] = [1, 2, 4, 24];
+
+const TIMER_HZ: u32 = 1_000_000;
+const MIDI_CLOCK_PPQN: u32 = 24;
+// Fall back to the internal clock after this many missing pulse intervals.
+const TIMEOUT_INTERVALS: u32 = 4;
+// Smoothed interval moves 1/2^SHIFT of the way towards each new measurement.
+const SMOOTHING_SHIFT: u32 = 2;
+// Jumps bigger than this (percent) bypass the smoothing so tempo changes lock quickly.
+const RESYNC_PERCENT: u32 = 25;
+
+static mut FOLLOWER: TempoFollower = TempoFollower::new();
+static mut PULSE_COUNT: u32 = 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ClockSource {
+    Internal,
+    /// 24 PPQN MIDI clock, with Start/Stop/Continue and Song Position Pointer controlling the
+    /// transport.
+    Midi,
+    /// Pulses on the clock input jack, `CLOCK_IN_PPQN` per quarter note.
+    Analog,
+}
+
+impl ClockSource {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ClockSource::Midi,
+            2 => ClockSource::Analog,
+            _ => ClockSource::Internal,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            ClockSource::Internal => ClockSource::Midi,
+            ClockSource::Midi => ClockSource::Analog,
+            ClockSource::Analog => ClockSource::Internal,
+        }
+    }
+}
+
+pub fn clock_source() -> ClockSource {
+    ClockSource::from_u8(CLOCK_SOURCE.load(Ordering::Relaxed))
+}
+
+pub fn set_clock_source(source: ClockSource) {
+    CLOCK_SOURCE.store(source as u8, Ordering::Relaxed);
+    cortex_m::interrupt::free(|_| unsafe {
+        reset_follower();
+        disarm_timeout();
+        external_clock_lost();
+    });
+}
+
+pub fn set_clock_in_ppqn(ppqn: u32) {
+    if CLOCK_IN_PPQN_OPTIONS.contains(&ppqn) {
+        CLOCK_IN_PPQN.store(ppqn, Ordering::Relaxed);
+        cortex_m::interrupt::free(|_| unsafe { reset_follower() });
+    }
+}
+
+/// Makes the next step pulse land on the first step. Called when playback starts.
+pub(crate) fn restart() {
+    unsafe { PULSE_COUNT = 0 };
+}
+
+/// Tracks the interval between clock pulses.
+struct TempoFollower {
+    last_pulse_us: Option<u32>,
+    interval_us: u32,
+}
+
+impl TempoFollower {
+    const fn new() -> Self {
+        Self {
+            last_pulse_us: None,
+            interval_us: 0,
+        }
+    }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn reset_follower() {
+    FOLLOWER.last_pulse_us = None;
+    FOLLOWER.interval_us = 0;
+}
+
+/// Returns the smoothed pulse interval, once there have been at least two pulses.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn follow_pulse(now_us: u32) -> Option<u32> {
+    let last = FOLLOWER.last_pulse_us;
+    FOLLOWER.last_pulse_us = Some(now_us);
+    let measured = now_us.wrapping_sub(last?).max(1);
+    FOLLOWER.interval_us = smooth_interval(FOLLOWER.interval_us, measured);
+    Some(FOLLOWER.interval_us)
+}
+
+fn smooth_interval(current: u32, measured: u32) -> u32 {
+    let diff = measured.abs_diff(current);
+    if current == 0 || diff > current / 100 * RESYNC_PERCENT {
+        measured
+    } else if measured > current {
+        current + (diff >> SMOOTHING_SHIFT)
+    } else {
+        current - (diff >> SMOOTHING_SHIFT)
+    }
+}
+
+/// Free-running 32-bit 1 MHz timer. CH1 captures rising edges on the clock input (PA0), CH2 is
+/// the timeout compare for detecting a stopped clock and CH3 captures the reset input (PA2).
+pub fn init_clock_input(tim5: TIM5, clocks: &Clocks) {
+    unsafe {
+        let rcc = &*pac::RCC::ptr();
+        rcc.apb1enr().modify(|_, w| w.tim5en().set_bit());
+        rcc.apb1rstr().modify(|_, w| w.tim5rst().set_bit());
+        rcc.apb1rstr().modify(|_, w| w.tim5rst().clear_bit());
+    }
+
+    tim5.cr1().modify(|_, w| w.cen().clear_bit());
+    let timclk = clocks.timclk1().raw();
+    let prescaler = (timclk / TIMER_HZ).saturating_sub(1);
+    tim5.psc().write(|w| unsafe { w.psc().bits(prescaler as u16) });
+    tim5.arr().write(|w| unsafe { w.bits(u32::MAX) });
+    tim5.ccmr1_input().modify(|_, w| w.cc1s().ti1().ic1f().fck_int_n8());
+    // Same filter as CH1, fCK_INT with N=8.
+    tim5.ccmr2_input().modify(|_, w| w.cc3s().ti3().ic3f().set(0b0011));
+    tim5.ccer().modify(|_, w| w.cc1p().clear_bit().cc1np().clear_bit().cc1e().set_bit());
+    tim5.ccer().modify(|_, w| w.cc3p().clear_bit().cc3np().clear_bit().cc3e().set_bit());
+    tim5.cnt().write(|w| unsafe { w.bits(0) });
+    tim5.egr().write(|w| w.ug().set_bit());
+    tim5.sr().modify(|_, w| w.cc1if().clear_bit().cc2if().clear_bit().uif().clear_bit());
+    tim5.sr().modify(|_, w| w.cc3if().clear_bit());
+    tim5.dier().modify(|_, w| w.cc1ie().set_bit().cc3ie().set_bit());
+    tim5.cr1().modify(|_, w| w.cen().set_bit());
+}
+
+#[interrupt]
+fn TIM5() {
+    let tim5 = unsafe { &*pac::TIM5::ptr() };
+    let sr = tim5.sr().read();
+    if sr.cc1if().bit_is_set() {
+        // Reading the capture clears the flag.
+        let now = tim5.ccr1().read().ccr().bits();
+        if clock_source() == ClockSource::Analog {
+            unsafe { on_clock_pulse(now, CLOCK_IN_PPQN.load(Ordering::Relaxed)) };
+        }
+    }
+    if sr.cc2if().bit_is_set() {
+        tim5.sr().modify(|_, w| w.cc2if().clear_bit());
+        unsafe {
+            disarm_timeout();
+            reset_follower();
+            external_clock_lost();
+        }
+    }
+    if sr.cc3if().bit_is_set() {
+        // Works with any clock source, like the reset input of a modular sequencer.
+        let _ = tim5.ccr3().read();
+        unsafe { external_reset() };
+    }
+}
+
+/// Called from the MIDI receive interrupt for every 0xF8. Uses the clock input timer as the
+/// time base, so MIDI and analog clock share the tempo follower and timeout.
+pub(crate) fn midi_clock_pulse() {
+    if clock_source() != ClockSource::Midi {
+        return;
+    }
+    // The MIDI interrupt runs below the timers, keep them out while the step state changes.
+    cortex_m::interrupt::free(|_| unsafe {
+        let now = (*pac::TIM5::ptr()).cnt().read().cnt().bits();
+        on_clock_pulse(now, MIDI_CLOCK_PPQN);
+    });
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn on_clock_pulse(now: u32, ppqn: u32) {
+    let Some(interval) = follow_pulse(now) else {
+        return;
+    };
+    arm_timeout(now.wrapping_add(interval.saturating_mul(TIMEOUT_INTERVALS)));
+
+    let ppqn = ppqn.max(1);
+    // Steps are 16ths, so a quarter note is 4 steps.
+    let beat_us = interval as u64 * ppqn as u64;
+    let bpm = ((60_000_000 + beat_us / 2) / beat_us) as u32;
+    let (numer, denom) = pulse_step_interval(interval, ppqn);
+    external_clock_tempo(numer, denom, bpm);
+
+    // At 4 PPQN and up a step spans one or more pulses, below that a pulse spans several steps.
+    let (pulses_per_step, steps_per_pulse) = if ppqn >= 4 { (ppqn / 4, 1) } else { (1, 4 / ppqn) };
+    if PULSE_COUNT.is_multiple_of(pulses_per_step) {
+        external_clock_step(steps_per_pulse as u8);
+    }
+    PULSE_COUNT = PULSE_COUNT.wrapping_add(1);
+}
+
+/// Step length in µs as `numer / denom` for a pulse `interval_us` apart. A beat is
+/// `interval_us * ppqn` and a step a quarter of that.
+fn pulse_step_interval(interval_us: u32, ppqn: u32) -> (u64, u32) {
+    (interval_us as u64 * ppqn as u64, 4)
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn arm_timeout(at: u32) {
+    let tim5 = &*pac::TIM5::ptr();
+    tim5.ccr2().write(|w| w.ccr().bits(at));
+    tim5.sr().modify(|_, w| w.cc2if().clear_bit());
+    tim5.dier().modify(|_, w| w.cc2ie().set_bit());
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn disarm_timeout() {
+    let tim5 = &*pac::TIM5::ptr();
+    tim5.dier().modify(|_, w| w.cc2ie().clear_bit());
+    tim5.sr().modify(|_, w| w.cc2if().clear_bit());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 125 BPM is a 480 ms beat, so every PPQN should come out at 120 ms steps.
+    #[test]
+    fn step_interval_at_each_ppqn() {
+        for ppqn in CLOCK_IN_PPQN_OPTIONS {
+            let (numer, denom) = pulse_step_interval(480_000 / ppqn, ppqn);
+            assert_eq!(numer / denom as u64, 120_000, "{ppqn} PPQN");
+        }
+    }
+
+    #[test]
+    fn step_interval_keeps_remainder() {
+        let (numer, denom) = pulse_step_interval(20_833, 24);
+        assert_eq!((numer / denom as u64, numer % denom as u64), (124_998, 0));
+        let (numer, denom) = pulse_step_interval(333_333, 1);
+        assert_eq!((numer / denom as u64, numer % denom as u64), (83_333, 1));
+    }
+}
@@ -0,0 +1,90 @@
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+
+use crate::sequencer::{LfoShape, MAX_TRACKS};
+
+// DAC8568 outputs 0-5 V over the full 16-bit range, so at 1 V/oct we get 60 semitones.
+pub const CV_MIN: u16 = 0;
+pub const CV_MAX: u16 = 0xFFFF;
+pub const CV_MID: u16 = 0x8000;
+pub const CV_BASE_NOTE: u8 = 24; // C1 = 0 V
+const CV_SEMITONES: u32 = 60;
+
+static CV_OUT: [AtomicU16; MAX_TRACKS] = [const { AtomicU16::new(0) }; MAX_TRACKS];
+static CV_PENDING: AtomicU8 = AtomicU8::new(0);
+
+// Quarter sine wave, 64 segments, amplitude 32767.
+const SINE_QUARTER: [i32; 65] = [
+    0, 804, 1608, 2410, 3212, 4011, 4808, 5602,
+    6393, 7179, 7962, 8739, 9512, 10278, 11039, 11793,
+    12539, 13279, 14010, 14732, 15446, 16151, 16846, 17530,
+    18204, 18868, 19519, 20159, 20787, 21403, 22005, 22594,
+    23170, 23731, 24279, 24811, 25329, 25832, 26319, 26790,
+    27245, 27683, 28105, 28510, 28898, 29268, 29621, 29956,
+    30273, 30571, 30852, 31113, 31356, 31580, 31785, 31971,
+    32137, 32285, 32412, 32521, 32609, 32678, 32728, 32757,
+    32767,
+];
+
+/// Store a new DAC code for a track. Safe to call from the timer ISR.
+pub fn write(track_index: usize, value: u16) {
+    let previous = CV_OUT[track_index].swap(value, Ordering::Relaxed);
+    if previous != value {
+        CV_PENDING.fetch_or(1 << track_index, Ordering::Release);
+    }
+}
+
+// There is no DAC8568 driver yet, so nothing sends the codes out. The driver should take the
+// pending mask and read the changed channels. Until then these stay private.
+#[allow(dead_code)]
+fn read(track_index: usize) -> u16 {
+    CV_OUT[track_index].load(Ordering::Relaxed)
+}
+
+/// Returns the mask of channels that changed since the last call.
+#[allow(dead_code)]
+fn take_pending() -> u8 {
+    CV_PENDING.swap(0, Ordering::Acquire)
+}
+
+pub fn pitch_to_cv(pitch: u8) -> u16 {
+    let semitones = pitch.saturating_sub(CV_BASE_NOTE) as u32;
+    (semitones * CV_MAX as u32 / CV_SEMITONES).min(CV_MAX as u32) as u16
+}
+
+/// `phase` covers one full LFO cycle over the u32 range. `held` is the current sample & hold
+/// value, which the caller refreshes once per cycle.
+pub fn lfo_value(shape: LfoShape, phase: u32, held: u16) -> u16 {
+    match shape {
+        LfoShape::Sine => sine(phase),
+        LfoShape::Triangle => {
+            let x = phase >> 15;
+            if x & 0x1_0000 != 0 {
+                (0xFFFF - (x & 0xFFFF)) as u16
+            } else {
+                x as u16
+            }
+        }
+        LfoShape::Saw => (phase >> 16) as u16,
+        LfoShape::Square => {
+            if phase < 0x8000_0000 { CV_MAX } else { CV_MIN }
+        }
+        LfoShape::SampleHold => held,
+    }
+}
+
+fn sine(phase: u32) -> u16 {
+    let quadrant = phase >> 30;
+    let mut pos = (phase >> 16) & 0x3FFF;
+    if quadrant & 1 != 0 {
+        pos = 0x4000 - pos;
+    }
+    let index = (pos >> 8) as usize;
+    let frac = (pos & 0xFF) as i32;
+    let a = SINE_QUARTER[index];
+    let b = SINE_QUARTER[(index + 1).min(SINE_QUARTER.len() - 1)];
+    let mut value = a + (((b - a) * frac) >> 8);
+    if quadrant >= 2 {
+        value = -value;
+    }
+    (CV_MID as i32 + value) as u16
+}
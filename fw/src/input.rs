@@ -1,7 +1,23 @@
+use crate::clipboard::{copy_pattern, copy_steps, copy_track, paste};
+use crate::clock::{clock_source, set_clock_source};
 use crate::sequencer::{
-    mark_dirty, select_step, set_step, toggle_playback, DIRTY_NOTE_DATA,
-    DIRTY_PATTERN, DIRTY_RT_CACHE, SequencerState,
+    advance_selected_step, bind_cc, clear_steps, external_song_position, fill_press,
+    fill_release, mark_dirty, pause_playback, queue_pattern, rebuild_fill_cache, recall_scene,
+    record_hit, record_note, roll_press, roll_release, save_scene, select_step,
+    select_step_range, set_bpm, set_clock_output, set_cv_mode, set_edit_pattern, set_envelope,
+    set_lfo, set_loop_window, set_midi_channel, set_mute_scope, set_muted, set_offset,
+    set_output_mode, set_record_mode, set_reset_mode, set_reset_output, set_rest, set_soloed,
+    set_song_position, set_step, set_step_field, set_swing, set_tie, set_transpose,
+    set_trigger_ms, start_playback, stop_playback, toggle_playback, ChangeAt, EnvelopeMode,
+    MuteScope, Param, RecordMode, StepField, DEFAULT_VELOCITY, DIRTY_NOTE_DATA, DIRTY_RT_CACHE,
+    LFO_RATES, MAX_BPM, MAX_CLOCK_PULSE_MS, MAX_PATTERNS, MAX_STEPS, MAX_SWING, MAX_TRIGGER_MS,
+    MIN_BPM, MIN_CLOCK_PULSE_MS, MIN_SWING, MIN_TRIGGER_MS, PLAYING, SequencerState,
 };
+use crate::midi::MidiMessage;
+use crate::playhead::ROLL_LENGTHS;
+use crate::transform::{transform_tracks, Transform};
+use crate::undo::{record_changes, redo, undo, Snapshot};
+use core::sync::atomic::Ordering;
 use crate::utils::iter_bits_u8;
 use rtt_target::rprintln;
 
@@ -13,48 +29,234 @@ pub enum Button {
     Note(u8),
     OctaveUp,
     OctaveDown,
+    OutputMode,
+    CvMode,
+    LfoShape,
+    ClockSource,
+    ClockOutRate,
     Play,
     Stop,
+    Record,
+    RecordMicro,
+    Trigger,
+    Rest,
+    Tie,
+    NextParam,
+    MidiLearn,
+    Undo,
+    Redo,
+    CopySteps,
+    CopyTrack,
+    CopyPattern,
+    Paste,
+    Transform(Transform),
+    Shift,
+    Follow,
+    // Toggle on the selected tracks.
+    Mute,
+    Solo,
+    QuantizeMutes,
+    MuteScope,
+    Scene(u8), // 0-7, with Shift saves instead of recalling.
+    QuantizeScenes,
+    Fill, // With Shift makes the pattern being edited the fill pattern.
+    Roll,
+    RollLength,
+    // Bulk edits of the selected steps on the selected tracks.
+    Adjust(StepField, i8),
+    Clear,
+    // Loops the selected tracks over the selected steps, with Shift over all of them again.
+    LoopWindow,
+    // Starts the selected tracks from the selected step, with Shift cycles their reset mode.
+    Offset,
 }
 
+/// Handles a button press. Any change it makes to the visible pattern becomes one undo step.
 pub fn handle_button_press(button: Button, sequencer_state: &mut SequencerState) {
+    match button {
+        Button::Undo => {
+            let done = undo(sequencer_state);
+            rprintln!("{}", if done { "Undo" } else { "Nothing to undo" });
+        }
+        Button::Redo => {
+            let done = redo(sequencer_state);
+            rprintln!("{}", if done { "Redo" } else { "Nothing to redo" });
+        }
+        _ => undoable(sequencer_state, |sequencer_state| press(button, sequencer_state)),
+    }
+}
+
+/// Runs `edit` and records what it changed in the visible pattern as one undo step. Live
+/// recording goes to the playing pattern, so that one is recorded as well.
+fn undoable(sequencer_state: &mut SequencerState, edit: impl FnOnce(&mut SequencerState)) {
+    let before = Snapshot::take(sequencer_state);
+    edit(sequencer_state);
+    record_changes(sequencer_state, &before);
+}
+
+fn press(button: Button, sequencer_state: &mut SequencerState) {
     match button {
         Button::Step(n) => {
-            let tracks = sequencer_state.selected_tracks;
-            let pattern = &mut sequencer_state.patterns[sequencer_state.visible_pattern as usize];
-            for track_index in iter_bits_u8(tracks) {
-                let step = &mut pattern.tracks[track_index as usize].steps[n as usize];
-                step.active = !step.active;
-                rprintln!("Step {} on track {}: {}", n, track_index, step.active);
+            // With a step held, or Shift from the selected step, select the range up to this one.
+            let anchor = match (sequencer_state.held_step, sequencer_state.shift_held) {
+                (Some(held), _) => Some(held),
+                (None, true) => sequencer_state.selected_step,
+                (None, false) => None,
+            };
+            match anchor {
+                Some(from) if from != n => {
+                    select_step_range(sequencer_state, from, n);
+                    sequencer_state.held_step_used = true;
+                    rprintln!("Selected steps {:016b}", sequencer_state.edit.steps);
+                }
+                _ => {
+                    sequencer_state.held_step = Some(n);
+                    sequencer_state.held_step_used = false;
+                    select_step(sequencer_state, n);
+                }
+            }
+        }
+        Button::Shift => {
+            sequencer_state.shift_held = true;
+        }
+        Button::Mute | Button::Solo => {
+            // All selected tracks follow the first one.
+            let tracks = sequencer_state.edit.tracks;
+            let mutes = sequencer_state.mutes();
+            if matches!(button, Button::Mute) {
+                let muted = mutes.muted & (1 << tracks.trailing_zeros()) == 0;
+                set_muted(sequencer_state, tracks, muted);
+                rprintln!("Muted {:08b}", sequencer_state.mutes().muted);
+            } else {
+                let soloed = mutes.soloed & (1 << tracks.trailing_zeros()) == 0;
+                set_soloed(sequencer_state, tracks, soloed);
+                rprintln!("Soloed {:08b}", sequencer_state.mutes().soloed);
+            }
+        }
+        Button::QuantizeMutes => {
+            let settings = &mut sequencer_state.settings;
+            settings.quantize_mutes = !settings.quantize_mutes;
+            rprintln!("Quantize mutes: {}", settings.quantize_mutes);
+        }
+        Button::MuteScope => {
+            let scope = match sequencer_state.settings.mute_scope {
+                MuteScope::Global => MuteScope::Pattern,
+                MuteScope::Pattern => MuteScope::Global,
+            };
+            set_mute_scope(sequencer_state, scope);
+            rprintln!("Mute scope: {:?}", scope);
+        }
+        Button::Scene(n) if sequencer_state.shift_held => {
+            save_scene(sequencer_state, n);
+            rprintln!("Saved scene {}", n);
+        }
+        Button::Scene(n) => {
+            let at = if sequencer_state.settings.quantize_scenes {
+                ChangeAt::PatternEnd
+            } else {
+                ChangeAt::Now
+            };
+            if recall_scene(sequencer_state, n, at) {
+                rprintln!("Recalled scene {}", n);
+            } else {
+                rprintln!("Scene {} is empty", n);
             }
-            mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
-            select_step(sequencer_state, n);
+        }
+        Button::QuantizeScenes => {
+            let settings = &mut sequencer_state.settings;
+            settings.quantize_scenes = !settings.quantize_scenes;
+            rprintln!("Quantize scenes: {}", settings.quantize_scenes);
+        }
+        Button::Fill if sequencer_state.shift_held => {
+            sequencer_state.fill_pattern = Some(sequencer_state.edit.pattern);
+            rebuild_fill_cache(sequencer_state);
+            rprintln!("Fill pattern {}", sequencer_state.edit.pattern);
+        }
+        Button::Fill if sequencer_state.fill_pattern.is_some() => {
+            fill_press();
+            rprintln!("Fill");
+        }
+        Button::Fill => {
+            rprintln!("No fill pattern");
+        }
+        Button::Roll => {
+            roll_press(sequencer_state.roll_steps);
+            rprintln!("Roll {} steps", sequencer_state.roll_steps);
+        }
+        Button::RollLength => {
+            let steps = sequencer_state.roll_steps;
+            let index = ROLL_LENGTHS.iter().position(|&len| len == steps).unwrap_or(0);
+            sequencer_state.roll_steps = ROLL_LENGTHS[(index + 1) % ROLL_LENGTHS.len()];
+            rprintln!("Roll length: {}", sequencer_state.roll_steps);
+        }
+        Button::Follow => {
+            sequencer_state.follow = !sequencer_state.follow;
+            rprintln!("Follow playback: {}", sequencer_state.follow);
+        }
+        Button::Track(n) if sequencer_state.shift_held => {
+            sequencer_state.toggle_track(n);
+            rprintln!("Selected tracks {:08b}", sequencer_state.edit.tracks);
+        }
+        Button::Track(n) if is_recording(sequencer_state) => {
+            let steps = record_hit(sequencer_state, 1 << n);
+            rprintln!("Recorded track {} step {}", n, steps[n as usize]);
         }
         Button::Track(n) => {
             sequencer_state.select_only_track(n);
-            // TODO: it should be possible to select multiple tracks using `toggle_track()` with
-            // some modifier key.
             rprintln!("Selected track {}", n);
         }
         Button::Pattern(n) => {
-            sequencer_state.visible_pattern = n;
-            mark_dirty(DIRTY_PATTERN);
-            rprintln!("Selected pattern {}", n);
+            set_edit_pattern(sequencer_state, n);
+            rprintln!("Editing pattern {}", n);
         }
         Button::Play => {
             let playing = toggle_playback();
             rprintln!("{}", if playing { "Play" } else { "Pause" });
         }
         Button::Stop => {
+            stop_playback(sequencer_state);
             rprintln!("Stop");
         }
+        Button::Record => {
+            let mode = sequencer_state.record.next();
+            set_record_mode(sequencer_state, mode);
+            rprintln!("Record: {:?}", mode);
+        }
+        Button::RecordMicro => {
+            sequencer_state.record_micro = !sequencer_state.record_micro;
+            rprintln!("Record microtiming: {}", sequencer_state.record_micro);
+        }
+        Button::Trigger => {
+            if is_recording(sequencer_state) {
+                let steps = record_hit(sequencer_state, sequencer_state.edit.tracks);
+                rprintln!("Recorded steps {:?}", steps);
+            }
+        }
         Button::Note(n) => {
-            rprintln!("note: {}", n);
-            let selected_step = match sequencer_state.selected_step {
-                Some(s) => s,
-                None => return,
+            enter_note(sequencer_state, n, DEFAULT_VELOCITY);
+        }
+        Button::Rest | Button::Tie => {
+            if sequencer_state.record != RecordMode::Step {
+                return;
+            }
+            let Some(step) = sequencer_state.selected_step else {
+                return;
             };
-            set_step(sequencer_state, sequencer_state.selected_tracks, selected_step, n);
+            let tracks = sequencer_state.edit.tracks;
+            if matches!(button, Button::Rest) {
+                set_rest(sequencer_state, tracks, step);
+            } else {
+                set_tie(sequencer_state, tracks, step);
+            }
+            advance_selected_step(sequencer_state);
+        }
+        Button::NextParam => {
+            sequencer_state.param_cursor = sequencer_state.param_cursor.next();
+            rprintln!("Param: {:?}", sequencer_state.param_cursor);
+        }
+        Button::MidiLearn => {
+            sequencer_state.learn = !sequencer_state.learn;
+            rprintln!("MIDI learn: {}", sequencer_state.learn);
         }
         Button::OctaveUp => {
             rprintln!("octave up");
@@ -62,6 +264,329 @@ pub fn handle_button_press(button: Button, sequencer_state: &mut SequencerState)
         Button::OctaveDown => {
             rprintln!("octave down");
         }
+        Button::OutputMode => {
+            // Cycle all selected tracks to the mode following the first selected track's mode.
+            let tracks = sequencer_state.edit.tracks;
+            let first = tracks.trailing_zeros() as usize;
+            let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+            let mode = pattern.tracks[first].output_mode.next();
+            set_output_mode(sequencer_state, tracks, mode);
+            rprintln!("Output mode: {:?}", mode);
+        }
+        Button::CvMode => {
+            let tracks = sequencer_state.edit.tracks;
+            let first = tracks.trailing_zeros() as usize;
+            let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+            let mode = pattern.tracks[first].cv_mode.next();
+            set_cv_mode(sequencer_state, tracks, mode);
+            rprintln!("CV mode: {:?}", mode);
+        }
+        Button::LfoShape => {
+            let tracks = sequencer_state.edit.tracks;
+            let first = tracks.trailing_zeros() as usize;
+            let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+            let mut lfo = pattern.tracks[first].lfo;
+            lfo.shape = lfo.shape.next();
+            set_lfo(sequencer_state, tracks, lfo);
+            rprintln!("LFO shape: {:?}", lfo.shape);
+        }
+        Button::ClockSource => {
+            let source = clock_source().next();
+            set_clock_source(source);
+            rprintln!("Clock source: {:?}", source);
+        }
+        Button::ClockOutRate => {
+            let mut clock_out = sequencer_state.settings.clock_out;
+            clock_out.rate = clock_out.rate.next();
+            set_clock_output(sequencer_state, clock_out);
+            rprintln!("Clock out rate: {:?}", clock_out.rate);
+        }
+        Button::CopySteps => {
+            // The span of selected steps, or from the one selected step to the end of the first
+            // selected track.
+            let steps = sequencer_state.edit.steps;
+            let start = if steps == 0 { 0 } else { steps.trailing_zeros() as u8 };
+            let end = match steps.count_ones() {
+                0 | 1 => MAX_STEPS as u8 - 1,
+                _ => 15 - steps.leading_zeros() as u8,
+            };
+            let pattern = sequencer_state.edit.pattern;
+            let track = sequencer_state.edit.tracks.trailing_zeros() as u8;
+            copy_steps(sequencer_state, pattern, track, start, end);
+            rprintln!("Copied steps {}-{} of track {}", start, end, track);
+        }
+        Button::CopyTrack => {
+            let pattern = sequencer_state.edit.pattern;
+            let track = sequencer_state.edit.tracks.trailing_zeros() as u8;
+            copy_track(sequencer_state, pattern, track);
+            rprintln!("Copied track {}", track);
+        }
+        Button::CopyPattern => {
+            let pattern = sequencer_state.edit.pattern;
+            copy_pattern(sequencer_state, pattern);
+            rprintln!("Copied pattern {}", pattern);
+        }
+        Button::Paste => {
+            let pattern = sequencer_state.edit.pattern;
+            let tracks = sequencer_state.edit.tracks;
+            let steps = sequencer_state.edit.steps;
+            let start = if steps == 0 { 0 } else { steps.trailing_zeros() as u8 };
+            if paste(sequencer_state, pattern, tracks, start) {
+                rprintln!("Pasted into pattern {}", pattern);
+            } else {
+                rprintln!("Clipboard is empty");
+            }
+        }
+        Button::Transform(transform) => {
+            transform_tracks(sequencer_state, sequencer_state.edit.tracks, transform);
+            rprintln!("{:?}", transform);
+        }
+        Button::Adjust(field, delta) => {
+            // Every selected step gets the first one's value, adjusted.
+            let tracks = sequencer_state.edit.tracks;
+            let steps = sequencer_state.edit.steps;
+            if steps == 0 {
+                return;
+            }
+            let first_track = tracks.trailing_zeros() as usize;
+            let first_step = steps.trailing_zeros() as usize;
+            let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+            let value = field.get(&pattern.tracks[first_track].steps[first_step]);
+            let value = value.saturating_add_signed(delta);
+            set_step_field(sequencer_state, tracks, steps, field, value);
+            rprintln!("{:?}: {}", field, value);
+        }
+        Button::Clear => {
+            let (tracks, steps) = (sequencer_state.edit.tracks, sequencer_state.edit.steps);
+            clear_steps(sequencer_state, tracks, steps);
+            rprintln!("Cleared steps {:016b}", steps);
+        }
+        Button::LoopWindow => {
+            let (tracks, steps) = (sequencer_state.edit.tracks, sequencer_state.edit.steps);
+            let (start, end) = if sequencer_state.shift_held {
+                (0, MAX_STEPS as u8 - 1)
+            } else if steps != 0 {
+                (steps.trailing_zeros() as u8, 15 - steps.leading_zeros() as u8)
+            } else {
+                rprintln!("No steps selected");
+                return;
+            };
+            set_loop_window(sequencer_state, tracks, start, end);
+            rprintln!("Loop steps {}-{}", start, end);
+        }
+        Button::Offset if sequencer_state.shift_held => {
+            let tracks = sequencer_state.edit.tracks;
+            let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+            let first_track = tracks.trailing_zeros() as usize;
+            let mode = pattern.tracks[first_track].reset_mode.next();
+            set_reset_mode(sequencer_state, tracks, mode);
+            rprintln!("Reset: {:?}", mode);
+        }
+        Button::Offset => {
+            let Some(step) = sequencer_state.selected_step else {
+                rprintln!("No step selected");
+                return;
+            };
+            set_offset(sequencer_state, sequencer_state.edit.tracks, step);
+            rprintln!("Start at step {}", step);
+        }
+        Button::Undo | Button::Redo => {}
+    }
+}
+
+/// Handles a button release. Step buttons toggle on release, unless they were held to select
+/// a range.
+pub fn handle_button_release(button: Button, sequencer_state: &mut SequencerState) {
+    match button {
+        Button::Shift => {
+            sequencer_state.shift_held = false;
+        }
+        Button::Fill => fill_release(),
+        Button::Roll => roll_release(),
+        Button::Step(n) if sequencer_state.held_step == Some(n) => {
+            sequencer_state.held_step = None;
+            if !sequencer_state.held_step_used {
+                undoable(sequencer_state, |sequencer_state| toggle_step(sequencer_state, n));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn toggle_step(sequencer_state: &mut SequencerState, n: u8) {
+    let tracks = sequencer_state.edit.tracks;
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        let step = &mut pattern.tracks[track_index as usize].steps[n as usize];
+        step.active = !step.active;
+        rprintln!("Step {} on track {}: {}", n, track_index, step.active);
+    }
+    mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
+}
+
+/// A note from the keys or from MIDI in. Sets the selected step of the selected tracks, or
+/// while recording, the step under the playhead.
+pub fn handle_note(sequencer_state: &mut SequencerState, pitch: u8, velocity: u8) {
+    undoable(sequencer_state, |sequencer_state| enter_note(sequencer_state, pitch, velocity));
+}
+
+fn enter_note(sequencer_state: &mut SequencerState, pitch: u8, velocity: u8) {
+    rprintln!("note: {} velocity: {}", pitch, velocity);
+    let tracks = sequencer_state.edit.tracks;
+    if is_recording(sequencer_state) {
+        record_note(sequencer_state, tracks, pitch, velocity);
+    } else {
+        set_step(sequencer_state, tracks, sequencer_state.edit.steps, pitch, velocity);
+    }
+    if sequencer_state.record == RecordMode::Step {
+        advance_selected_step(sequencer_state);
+    }
+}
+
+fn is_recording(sequencer_state: &SequencerState) -> bool {
+    sequencer_state.record.is_live() && PLAYING.load(Ordering::Relaxed)
+}
+
+/// While MIDI learn is on, the first CC binds to the parameter under the cursor. Otherwise the
+/// CC sets every parameter bound to it.
+pub fn handle_control_change(
+    sequencer_state: &mut SequencerState,
+    channel: u8,
+    control: u8,
+    value: u8,
+) {
+    if sequencer_state.learn {
+        let param = sequencer_state.param_cursor;
+        if bind_cc(sequencer_state, channel, control, param) {
+            rprintln!("CC {} on channel {} -> {:?}", control, channel, param);
+        } else {
+            rprintln!("No free CC bindings");
+        }
+        sequencer_state.learn = false;
+        return;
+    }
+    for binding in sequencer_state.settings.cc_bindings {
+        match binding {
+            Some(b) if b.channel == channel && b.control == control => {
+                set_param(sequencer_state, b.param, value);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Start, Stop, Continue and Song Position Pointer from the device we take our clock from.
+pub fn handle_transport(sequencer_state: &mut SequencerState, message: MidiMessage) {
+    let playing = PLAYING.load(Ordering::Relaxed);
+    match message {
+        // From the top of the song, in song mode its first entry.
+        MidiMessage::Start => {
+            if playing {
+                pause_playback();
+            }
+            set_song_position(sequencer_state, 0);
+            start_playback();
+        }
+        MidiMessage::Continue if !playing => start_playback(),
+        MidiMessage::Stop if playing => pause_playback(),
+        MidiMessage::SongPosition(position) => external_song_position(sequencer_state, position),
+        _ => {}
+    }
+}
+
+/// Program changes queue the pattern with the same number.
+pub fn handle_program_change(sequencer_state: &mut SequencerState, program: u8) {
+    if (program as usize) < MAX_PATTERNS {
+        queue_pattern(sequencer_state, program);
+        rprintln!("Queued pattern {}", program);
+    }
+}
+
+/// Sets `param` from a 0-127 controller value, scaled to the parameter's range.
+fn set_param(sequencer_state: &mut SequencerState, param: Param, value: u8) {
+    let value = value.min(127) as u32;
+    let scale = |min: u32, max: u32| min + value * (max - min) / 127;
+    let tracks = sequencer_state.edit.tracks;
+    let first = tracks.trailing_zeros() as usize;
+    let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    let mut envelope = pattern.tracks[first].envelope;
+    let mut lfo = pattern.tracks[first].lfo;
+    let pulse_ms = scale(MIN_CLOCK_PULSE_MS as u32, MAX_CLOCK_PULSE_MS as u32) as u8;
+    match param {
+        Param::Bpm => set_bpm(scale(MIN_BPM, MAX_BPM)),
+        Param::Swing => {
+            let swing = scale(MIN_SWING as u32, MAX_SWING as u32) as u8;
+            set_swing(sequencer_state, swing);
+        }
+        // Centered, one semitone per value.
+        Param::Transpose => set_transpose(sequencer_state, (value as i32 - 64) as i8),
+        Param::Mute(track) => set_muted(sequencer_state, 1 << track, value >= 64),
+        Param::TriggerLength => {
+            let trigger_ms = scale(MIN_TRIGGER_MS as u32, MAX_TRIGGER_MS as u32) as u8;
+            set_trigger_ms(sequencer_state, tracks, trigger_ms);
+        }
+        Param::LfoRate => {
+            lfo.rate = LFO_RATES[value as usize * LFO_RATES.len() / 128];
+            set_lfo(sequencer_state, tracks, lfo);
+        }
+        Param::EnvAttack | Param::EnvDecay | Param::EnvRelease => {
+            // Squared for finer control of short times, up to 2 s.
+            let ms = (value * value * 2000 / (127 * 127)) as u16;
+            match param {
+                Param::EnvAttack => envelope.attack_ms = ms,
+                Param::EnvDecay => envelope.decay_ms = ms,
+                _ => envelope.release_ms = ms,
+            }
+            set_envelope(sequencer_state, tracks, envelope);
+        }
+        Param::EnvSustain => {
+            envelope.sustain = scale(0, 100) as u8;
+            set_envelope(sequencer_state, tracks, envelope);
+        }
+        Param::EnvMode => {
+            envelope.mode = if value >= 64 { EnvelopeMode::Ad } else { EnvelopeMode::Adsr };
+            set_envelope(sequencer_state, tracks, envelope);
+        }
+        // Channels 1-16 in steps of 8.
+        Param::MidiChannel => set_midi_channel(sequencer_state, tracks, (value / 8) as u8),
+        Param::ClockOutPulse | Param::ClockOutInverted => {
+            let mut clock_out = sequencer_state.settings.clock_out;
+            match param {
+                Param::ClockOutPulse => clock_out.pulse_ms = pulse_ms,
+                _ => clock_out.inverted = value >= 64,
+            }
+            set_clock_output(sequencer_state, clock_out);
+        }
+        Param::ResetOutPulse | Param::ResetOutInverted => {
+            let mut reset_out = sequencer_state.settings.reset_out;
+            match param {
+                Param::ResetOutPulse => reset_out.pulse_ms = pulse_ms,
+                _ => reset_out.inverted = value >= 64,
+            }
+            set_reset_output(sequencer_state, reset_out);
+        }
+    }
+}
+
+/// Terminal input has no key releases, so Shift and Roll latch: one press holds them, the next
+/// releases them.
+#[cfg(feature = "keyboard-input")]
+pub fn handle_key(key: u8, sequencer_state: &mut SequencerState) {
+    match key_to_button(key) {
+        Some(Button::Shift) if sequencer_state.shift_held => {
+            handle_button_release(Button::Shift, sequencer_state);
+        }
+        Some(Button::Roll) if crate::sequencer::roll_held() => {
+            handle_button_release(Button::Roll, sequencer_state);
+        }
+        // Step keys release right away, holding a step for a range needs Shift instead. A fill
+        // plays for its one bar.
+        Some(button @ (Button::Step(_) | Button::Fill)) => {
+            handle_button_press(button, sequencer_state);
+            handle_button_release(button, sequencer_state);
+        }
+        Some(button) => handle_button_press(button, sequencer_state),
+        None => {}
     }
 }
 
@@ -111,8 +636,64 @@ pub fn key_to_button(key: u8) -> Option<Button> {
         b'+' => Some(Button::OctaveUp),
         b'-' => Some(Button::OctaveDown),
 
+        b'o' => Some(Button::OutputMode),
+        b'l' => Some(Button::CvMode),
+        b'L' => Some(Button::LfoShape),
+        b'K' => Some(Button::ClockSource),
+        b'P' => Some(Button::ClockOutRate),
+
         b' ' => Some(Button::Play),
-        b'x' => Some(Button::Stop),
+        b'X' => Some(Button::Stop),
+        b'R' => Some(Button::Record),
+        b'U' => Some(Button::RecordMicro),
+        b'T' => Some(Button::Trigger),
+        b'_' => Some(Button::Rest),
+        b'=' => Some(Button::Tie),
+        b'p' => Some(Button::NextParam),
+        b'M' => Some(Button::MidiLearn),
+        b'S' => Some(Button::Shift),
+        b'F' => Some(Button::Follow),
+        b'Q' => Some(Button::Mute),
+        b'W' => Some(Button::Solo),
+        b'E' => Some(Button::QuantizeMutes),
+        b'Y' => Some(Button::MuteScope),
+        b'a' => Some(Button::Scene(0)),
+        b'f' => Some(Button::Scene(1)),
+        b'k' => Some(Button::Scene(2)),
+        b'A' => Some(Button::Scene(3)),
+        b'G' => Some(Button::Scene(4)),
+        b'J' => Some(Button::Scene(5)),
+        b'O' => Some(Button::Scene(6)),
+        b'|' => Some(Button::Scene(7)),
+        b'\\' => Some(Button::QuantizeScenes),
+        b'~' => Some(Button::Fill),
+        b'`' => Some(Button::Roll),
+        b':' => Some(Button::RollLength),
+        b'(' => Some(Button::Adjust(StepField::GateLength, -10)),
+        b')' => Some(Button::Adjust(StepField::GateLength, 10)),
+        b';' => Some(Button::Adjust(StepField::Velocity, -8)),
+        b'\'' => Some(Button::Adjust(StepField::Velocity, 8)),
+        b',' => Some(Button::Adjust(StepField::Probability, -10)),
+        b'.' => Some(Button::Adjust(StepField::Probability, 10)),
+        b'Z' => Some(Button::Clear),
+        b'?' => Some(Button::LoopWindow),
+        b'"' => Some(Button::Offset),
+        b'u' => Some(Button::Undo),
+        b'i' => Some(Button::Redo),
+        b'C' => Some(Button::CopySteps),
+        b'B' => Some(Button::CopyTrack),
+        b'N' => Some(Button::CopyPattern),
+        b'V' => Some(Button::Paste),
+        b'<' => Some(Button::Transform(Transform::RotateLeft)),
+        b'>' => Some(Button::Transform(Transform::RotateRight)),
+        b'/' => Some(Button::Transform(Transform::Reverse)),
+        b'I' => Some(Button::Transform(Transform::Invert)),
+        b'[' => Some(Button::Transform(Transform::Transpose(-1))),
+        b']' => Some(Button::Transform(Transform::Transpose(1))),
+        b'{' => Some(Button::Transform(Transform::TransposeDegrees(-1))),
+        b'}' => Some(Button::Transform(Transform::TransposeDegrees(1))),
+        b'D' => Some(Button::Transform(Transform::DoubleLength)),
+        b'H' => Some(Button::Transform(Transform::HalveLength)),
         _ => None,
     }
 }
@@ -1,8 +1,17 @@
 #![no_std]
 pub mod bitmaps;
+pub mod clipboard;
+pub mod clock;
+pub mod cv;
 pub mod input;
+pub mod midi;
+pub mod midi_io;
+pub mod midi_uart;
 #[cfg(feature = "perf")]
 pub mod perf;
+pub mod playhead;
 pub mod render;
 pub mod sequencer;
+pub mod transform;
+pub mod undo;
 pub mod utils;
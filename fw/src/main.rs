@@ -6,14 +6,22 @@ use core::sync::atomic::Ordering;
 
 use crate::hal::{pac, prelude::*};
 use cortex_m_rt::entry;
+use seq_08::clock::init_clock_input;
+use seq_08::input::{
+    handle_control_change, handle_note, handle_program_change, handle_transport,
+};
+use seq_08::midi::MidiMessage;
+use seq_08::midi_io::receive;
+use seq_08::midi_uart::init_midi_uart;
 use seq_08::render::{
-    render, render_bpm, render_cells, render_column, render_pattern_indicator,
-    render_playhead_marker, render_track_label, CellHighlight,
+    render, render_bpm, render_cell, render_pattern_indicator, render_playhead_marker,
+    render_record_indicator, render_track_label, CellHighlight,
 };
 use seq_08::sequencer::{
-    init_step_timer, rebuild_rt_cache, set_bpm, take_dirty, CURRENT_STEP, DIRTY_BPM,
-    DIRTY_NOTE_DATA, DIRTY_PATTERN, DIRTY_RT_CACHE, DIRTY_STEP_SELECTION, DIRTY_TRACK_SELECTION,
-    SEQ, STEP_FLAG, PLAYING,
+    init_step_timer, rebuild_rt_cache, set_bpm, take_dirty, take_dirty_cells, track_steps,
+    update_queued_pattern, update_replace_record, update_view, CURRENT_STEP, DIRTY_BPM,
+    DIRTY_MUTES, DIRTY_NOTE_DATA, DIRTY_PATTERN, DIRTY_RECORD, DIRTY_RT_CACHE,
+    DIRTY_TRACK_SELECTION, MAX_TRACKS, SEQ, STEP_FLAG, PLAYING,
 };
 use seq_08::utils::{iter_bits_u8, iter_bits_u16};
 use embedded_hal_bus::spi::ExclusiveDevice;
@@ -21,7 +29,7 @@ use panic_halt as _;
 use stm32f4xx_hal::{self as hal, spi::Spi};
 
 #[cfg(feature = "keyboard-input")]
-use seq_08::input::{handle_button_press, key_to_button};
+use seq_08::input::handle_key;
 
 #[cfg(feature = "perf")]
 use seq_08::perf::{init_cycle_counter, measure_cycles};
@@ -38,8 +46,15 @@ fn main() -> ! {
         let clocks = rcc.cfgr.sysclk(100.MHz()).freeze();
         let gpioa = dp.GPIOA.split();
         let gpiob = dp.GPIOB.split();
+        let gpioc = dp.GPIOC.split();
 
         let _gate_out_1 = gpioa.pa10.into_push_pull_output(); 
+        let _clock_in = gpioa.pa0.into_alternate::<2>(); // TIM5_CH1
+        let _reset_in = gpioa.pa2.into_alternate::<2>(); // TIM5_CH3
+        let _clock_out = gpioc.pc10.into_push_pull_output();
+        let _reset_out = gpioc.pc11.into_push_pull_output();
+        let _midi_tx = gpioc.pc6.into_alternate::<8>(); // USART6_TX
+        let _midi_rx = gpioc.pc7.into_alternate::<8>(); // USART6_RX
         let sck = gpioa.pa5.into_alternate::<5>(); // SPI1_SCK
         let mosi = gpioa.pa7.into_alternate::<5>(); // SPI1_MOSI / SDO
         let miso = gpioa.pa6.into_alternate::<5>(); // SPI1_MISO / SDI
@@ -59,6 +74,14 @@ fn main() -> ! {
             cortex_m::peripheral::NVIC::unmask(pac::Interrupt::TIM3);
         }
 
+        // MIDI output must never delay the step timer, so it runs at a lower priority.
+        init_midi_uart(dp.USART6, &clocks);
+        let mut nvic = cp.NVIC;
+        unsafe {
+            nvic.set_priority(pac::Interrupt::USART6, 0x40);
+            cortex_m::peripheral::NVIC::unmask(pac::Interrupt::USART6);
+        }
+
         let spi_bus = Spi::new(
             dp.SPI1,
             (sck, miso, mosi),
@@ -83,6 +106,11 @@ fn main() -> ! {
         display.init(&mut delay).unwrap();
         display.clear_screen(0x00).unwrap();
 
+        init_clock_input(delay.release().release(), &clocks);
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(pac::Interrupt::TIM5);
+        }
+
         let sequencer_state = unsafe { &mut *(&raw mut SEQ) };
         set_bpm(140);
 
@@ -128,19 +156,44 @@ fn main() -> ! {
         #[cfg(feature = "keyboard-input")]
         rtt_target::set_print_channel(channels.up.0);
 
+        // Where the playhead marker is drawn. Rolls and song position jumps move the playhead
+        // anywhere, so this is what gets erased rather than the step before.
+        let mut marker_step: Option<u8> = None;
+
         loop {
             #[cfg(feature = "keyboard-input")]
             {
                 let mut buf = [0u8; 1];
                 if input_channel.read(&mut buf) > 0 {
-                    if let Some(button) = key_to_button(buf[0]) {
-                        handle_button_press(button, sequencer_state);
+                    handle_key(buf[0], sequencer_state);
+                }
+            }
+            while let Some(message) = receive() {
+                match message {
+                    MidiMessage::NoteOn { note, velocity, .. } => {
+                        handle_note(sequencer_state, note, velocity);
+                    }
+                    MidiMessage::ControlChange { channel, control, value } => {
+                        handle_control_change(sequencer_state, channel, control, value);
                     }
+                    MidiMessage::ProgramChange { program, .. } => {
+                        handle_program_change(sequencer_state, program);
+                    }
+                    MidiMessage::Start
+                    | MidiMessage::Continue
+                    | MidiMessage::Stop
+                    | MidiMessage::SongPosition(_) => handle_transport(sequencer_state, message),
+                    _ => {}
                 }
             }
+            update_queued_pattern(sequencer_state);
+            update_view(sequencer_state);
             let step_moved = STEP_FLAG.swap(false, Ordering::Acquire);
+            if step_moved {
+                update_replace_record(sequencer_state);
+            }
             let dirty = take_dirty();
-            let mut dirty_steps: u16 = 0;
+            let mut dirty_cells = take_dirty_cells();
             let mut dirty_labels = false;
 
             #[cfg(feature = "perf")]
@@ -159,12 +212,12 @@ fn main() -> ! {
                 rebuild_rt_cache(&sequencer_state);
             }
             let playing_step = CURRENT_STEP.load(Ordering::Relaxed);
-            let max_steps = sequencer_state.max_steps;
-            let prev_step = if playing_step == 0 { max_steps - 1 } else { playing_step - 1 };
-
-            if step_moved {
-                render_playhead_marker(&mut display, prev_step, false);
+            if step_moved && marker_step != Some(playing_step) {
+                if let Some(step) = marker_step {
+                    render_playhead_marker(&mut display, step, false);
+                }
                 render_playhead_marker(&mut display, playing_step, true);
+                marker_step = Some(playing_step);
             }
 
             #[cfg(not(feature = "keyboard-input"))]
@@ -174,20 +227,14 @@ fn main() -> ! {
                 cortex_m::asm::wfi();
                 continue
             }
-            if dirty & DIRTY_STEP_SELECTION != 0 {
-                if let Some(prev) = sequencer_state.prev_selected_step {
-                    dirty_steps |= 1 << prev;
-                }
-                if let Some(curr) = sequencer_state.selected_step {
-                    dirty_steps |= 1 << curr;
-                }
-            }
-            if dirty & (DIRTY_TRACK_SELECTION | DIRTY_NOTE_DATA) != 0 {
-                if let Some(curr) = sequencer_state.selected_step {
-                    dirty_steps |= 1 << curr;
+            if dirty & DIRTY_NOTE_DATA != 0 {
+                for track in iter_bits_u8(sequencer_state.edit.tracks) {
+                    dirty_cells[track as usize] |= sequencer_state.edit.steps;
                 }
             }
-            if dirty & DIRTY_TRACK_SELECTION != 0 {
+            if dirty & (DIRTY_TRACK_SELECTION | DIRTY_MUTES) != 0 {
+                // Selection, mutes and solos show across the whole grid.
+                dirty_cells = [u16::MAX; MAX_TRACKS];
                 dirty_labels = true;
             }
             if dirty & DIRTY_PATTERN != 0 {
@@ -196,28 +243,26 @@ fn main() -> ! {
             if dirty & DIRTY_BPM != 0 {
                 render_bpm(&mut display);
             }
-            // Render all dirty steps
-            if dirty_steps != 0 {
-                let selected_step = sequencer_state.selected_step;
-                let selected_tracks = sequencer_state.selected_tracks;
-                let all_tracks = sequencer_state.get_all_tracks();
-                let unselected_tracks = all_tracks & !selected_tracks;
-
-                for step in iter_bits_u16(dirty_steps) {
-                    let is_playing = step == playing_step;
-                    let is_selected = selected_step == Some(step);
-                    let base = if is_playing { CellHighlight::Playing } else { CellHighlight::None };
-                    if is_selected {
-                        render_cells(&mut display, &sequencer_state, step, selected_tracks, CellHighlight::Selected);
-                        render_cells(&mut display, &sequencer_state, step, unselected_tracks, base);
+            if dirty & DIRTY_RECORD != 0 {
+                render_record_indicator(&mut display, sequencer_state);
+            }
+            // Render only the dirty cells. Loop windows and offsets put each track on its own step.
+            let track_steps = track_steps();
+            for track in iter_bits_u8(sequencer_state.get_all_tracks()) {
+                for step in iter_bits_u16(dirty_cells[track as usize]) {
+                    let highlight = if sequencer_state.is_cell_selected(track, step) {
+                        CellHighlight::Selected
+                    } else if step == track_steps[track as usize] {
+                        CellHighlight::Playing
                     } else {
-                        render_column(&mut display, &sequencer_state, step, base);
-                    }
+                        CellHighlight::None
+                    };
+                    render_cell(&mut display, sequencer_state, track, step, highlight);
                 }
             }
             if dirty_labels {
                 for track in iter_bits_u8(sequencer_state.get_all_tracks()) {
-                    render_track_label(&mut display, track, sequencer_state.is_track_selected(track));
+                    render_track_label(&mut display, sequencer_state, track);
                 }
             }
         }
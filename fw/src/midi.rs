@@ -0,0 +1,406 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const NOTE_OFF: u8 = 0x80;
+pub const NOTE_ON: u8 = 0x90;
+pub const CONTROL_CHANGE: u8 = 0xB0;
+pub const PROGRAM_CHANGE: u8 = 0xC0;
+pub const SYSEX_START: u8 = 0xF0;
+pub const SYSEX_END: u8 = 0xF7;
+pub const SONG_POSITION: u8 = 0xF2;
+pub const TIMING_CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    /// 24 per quarter note.
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    /// Position in MIDI beats (16th notes) from the start of the song, 14 bits.
+    SongPosition(u16),
+}
+
+impl MidiMessage {
+    /// Wire bytes, only the first `len` are valid.
+    pub fn to_bytes(self) -> ([u8; 3], usize) {
+        match self {
+            MidiMessage::NoteOff { channel, note, velocity } => {
+                ([NOTE_OFF | (channel & 0x0F), note & 0x7F, velocity & 0x7F], 3)
+            }
+            MidiMessage::NoteOn { channel, note, velocity } => {
+                ([NOTE_ON | (channel & 0x0F), note & 0x7F, velocity & 0x7F], 3)
+            }
+            MidiMessage::ControlChange { channel, control, value } => {
+                ([CONTROL_CHANGE | (channel & 0x0F), control & 0x7F, value & 0x7F], 3)
+            }
+            MidiMessage::ProgramChange { channel, program } => {
+                ([PROGRAM_CHANGE | (channel & 0x0F), program & 0x7F, 0], 2)
+            }
+            MidiMessage::Clock => ([TIMING_CLOCK, 0, 0], 1),
+            MidiMessage::Start => ([START, 0, 0], 1),
+            MidiMessage::Continue => ([CONTINUE, 0, 0], 1),
+            MidiMessage::Stop => ([STOP, 0, 0], 1),
+            MidiMessage::SongPosition(beats) => {
+                ([SONG_POSITION, (beats & 0x7F) as u8, ((beats >> 7) & 0x7F) as u8], 3)
+            }
+        }
+    }
+}
+
+/// A way of getting MIDI out of the box.
+pub trait MidiTransport: Sync {
+    /// Queue a message for sending. Returns false if it was dropped.
+    fn send(&self, message: MidiMessage) -> bool;
+}
+
+impl<const N: usize> MidiTransport for Queue<MidiMessage, N> {
+    fn send(&self, message: MidiMessage) -> bool {
+        self.push(message)
+    }
+}
+
+/// Sends every outgoing message to all transports, so they all carry the same notes and
+/// clock.
+pub struct Router<'a> {
+    outputs: &'a [&'a dyn MidiTransport],
+}
+
+impl<'a> Router<'a> {
+    pub const fn new(outputs: &'a [&'a dyn MidiTransport]) -> Self {
+        Self { outputs }
+    }
+
+    /// Returns false if any transport dropped the message.
+    pub fn send(&self, message: MidiMessage) -> bool {
+        let mut sent = true;
+        for output in self.outputs {
+            sent &= output.send(message);
+        }
+        sent
+    }
+}
+
+/// Where a received message goes. The same for every transport.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Route {
+    /// Drives the tempo follower when the clock source is MIDI.
+    Clock,
+    /// Start, Stop, Continue and Song Position Pointer, handled in the main loop in the order
+    /// they came, since song positions are looked up in the song.
+    Transport,
+    /// Notes, CCs and program changes are handled in the main loop.
+    MainLoop,
+}
+
+pub fn route(message: &MidiMessage) -> Route {
+    match message {
+        MidiMessage::Clock => Route::Clock,
+        MidiMessage::Start
+        | MidiMessage::Continue
+        | MidiMessage::Stop
+        | MidiMessage::SongPosition(_) => Route::Transport,
+        _ => Route::MainLoop,
+    }
+}
+
+/// Byte-at-a-time MIDI parser with running status. Messages we don't use (aftertouch, pitch
+/// bend, SysEx, ...) are consumed and dropped.
+pub struct Parser {
+    status: u8, // Running status, 0 when there is none.
+    data: [u8; 2],
+    len: u8,
+    in_sysex: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            status: 0,
+            data: [0; 2],
+            len: 0,
+            in_sysex: false,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<MidiMessage> {
+        // Real-time messages can appear anywhere, even between data bytes, and don't touch the
+        // running status.
+        if byte >= 0xF8 {
+            return match byte {
+                TIMING_CLOCK => Some(MidiMessage::Clock),
+                START => Some(MidiMessage::Start),
+                CONTINUE => Some(MidiMessage::Continue),
+                STOP => Some(MidiMessage::Stop),
+                _ => None,
+            };
+        }
+        if byte & 0x80 != 0 {
+            self.len = 0;
+            self.in_sysex = byte == SYSEX_START;
+            // System common messages cancel the running status, channel messages set it.
+            self.status = if byte == SYSEX_END { 0 } else { byte };
+            return if data_len(byte) == 0 { self.complete() } else { None };
+        }
+        if self.in_sysex || self.status == 0 {
+            return None;
+        }
+        self.data[self.len as usize] = byte;
+        self.len += 1;
+        if self.len < data_len(self.status) {
+            return None;
+        }
+        self.len = 0;
+        self.complete()
+    }
+
+    fn complete(&mut self) -> Option<MidiMessage> {
+        let status = self.status;
+        let [d0, d1] = self.data;
+        if status >= 0xF0 {
+            // No running status for system common messages.
+            self.status = 0;
+        }
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            NOTE_OFF => Some(MidiMessage::NoteOff { channel, note: d0, velocity: d1 }),
+            // Note on with zero velocity is a note off, it's how running status saves bytes.
+            NOTE_ON if d1 == 0 => Some(MidiMessage::NoteOff { channel, note: d0, velocity: 0 }),
+            NOTE_ON => Some(MidiMessage::NoteOn { channel, note: d0, velocity: d1 }),
+            CONTROL_CHANGE => Some(MidiMessage::ControlChange { channel, control: d0, value: d1 }),
+            PROGRAM_CHANGE => Some(MidiMessage::ProgramChange { channel, program: d0 }),
+            _ if status == SONG_POSITION => {
+                Some(MidiMessage::SongPosition(d0 as u16 | (d1 as u16) << 7))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn data_len(status: u8) -> u8 {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        0xF0 => match status {
+            0xF1 | 0xF3 => 1,
+            SONG_POSITION => 2,
+            _ => 0,
+        },
+        _ => 2,
+    }
+}
+
+/// Lock-free single producer, single consumer ring buffer. Holds up to `N - 1` items.
+pub struct Queue<T, const N: usize> {
+    buf: UnsafeCell<[MaybeUninit<T>; N]>,
+    head: AtomicUsize, // Next slot to read.
+    tail: AtomicUsize, // Next slot to write.
+}
+
+// SAFETY: Only one context pushes and only one context pops, and a slot is handed over through
+// the release/acquire pair on `tail`/`head`.
+unsafe impl<T: Copy + Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns false if the queue is full, the item is dropped.
+    pub fn push(&self, item: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buf.get())[tail] = MaybeUninit::new(item) };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*self.buf.get())[head].assume_init() };
+        self.head.store((head + 1) % N, Ordering::Release);
+        Some(item)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> ([Option<MidiMessage>; 8], usize) {
+        let mut parser = Parser::new();
+        let mut out = [None; 8];
+        let mut count = 0;
+        for &byte in bytes {
+            if let Some(message) = parser.feed(byte) {
+                out[count] = Some(message);
+                count += 1;
+            }
+        }
+        (out, count)
+    }
+
+    #[test]
+    fn note_on_and_off() {
+        let (out, count) = parse(&[0x91, 60, 100, 0x81, 60, 64]);
+        assert_eq!(count, 2);
+        assert_eq!(out[0], Some(MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }));
+        assert_eq!(out[1], Some(MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 }));
+    }
+
+    #[test]
+    fn running_status() {
+        let (out, count) = parse(&[0x90, 60, 100, 62, 90, 60, 0]);
+        assert_eq!(count, 3);
+        assert_eq!(out[1], Some(MidiMessage::NoteOn { channel: 0, note: 62, velocity: 90 }));
+        assert_eq!(out[2], Some(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 }));
+    }
+
+    #[test]
+    fn realtime_between_data_bytes() {
+        let (out, count) = parse(&[0xB2, 7, 0xF8, 99, 0xFA, 8, 0xFC, 1]);
+        assert_eq!(count, 5);
+        assert_eq!(out[0], Some(MidiMessage::Clock));
+        assert_eq!(out[1], Some(MidiMessage::ControlChange { channel: 2, control: 7, value: 99 }));
+        assert_eq!(out[2], Some(MidiMessage::Start));
+        assert_eq!(out[3], Some(MidiMessage::Stop));
+        assert_eq!(out[4], Some(MidiMessage::ControlChange { channel: 2, control: 8, value: 1 }));
+    }
+
+    #[test]
+    fn song_position() {
+        let (out, count) = parse(&[0xF2, 0x10, 0x02, 0xFB]);
+        assert_eq!(count, 2);
+        assert_eq!(out[0], Some(MidiMessage::SongPosition(0x110)));
+        assert_eq!(out[1], Some(MidiMessage::Continue));
+    }
+
+    #[test]
+    fn system_common_cancels_running_status() {
+        let (_, count) = parse(&[0x90, 60, 100, 0xF2, 0, 0, 62, 100]);
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn ignores_sysex_and_unused_messages() {
+        let (out, count) =
+            parse(&[0xF0, 0x7E, 0x01, 0xF8, 0x02, 0xF7, 0xD0, 5, 0xE0, 0, 64, 0x90, 1, 2]);
+        assert_eq!(count, 2);
+        assert_eq!(out[0], Some(MidiMessage::Clock));
+        assert_eq!(out[1], Some(MidiMessage::NoteOn { channel: 0, note: 1, velocity: 2 }));
+    }
+
+    #[test]
+    fn program_change_running_status() {
+        let (out, count) = parse(&[0xC4, 3, 7]);
+        assert_eq!(count, 2);
+        assert_eq!(out[0], Some(MidiMessage::ProgramChange { channel: 4, program: 3 }));
+        assert_eq!(out[1], Some(MidiMessage::ProgramChange { channel: 4, program: 7 }));
+    }
+
+    #[test]
+    fn stray_data_bytes_are_dropped() {
+        let (out, count) = parse(&[60, 100, 0x90, 60, 100]);
+        assert_eq!(count, 1);
+        assert_eq!(out[0], Some(MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }));
+    }
+
+    struct MockTransport {
+        sent: Queue<MidiMessage, 4>,
+    }
+
+    impl MidiTransport for MockTransport {
+        fn send(&self, message: MidiMessage) -> bool {
+            self.sent.push(message)
+        }
+    }
+
+    #[test]
+    fn router_sends_to_all_transports() {
+        let din = MockTransport { sent: Queue::new() };
+        let other = MockTransport { sent: Queue::new() };
+        let outputs: [&dyn MidiTransport; 2] = [&din, &other];
+        let router = Router::new(&outputs);
+        let note = MidiMessage::NoteOn { channel: 0, note: 60, velocity: 1 };
+        assert!(router.send(note));
+        assert!(router.send(MidiMessage::Clock));
+        for transport in [&din, &other] {
+            assert_eq!(transport.sent.pop(), Some(note));
+            assert_eq!(transport.sent.pop(), Some(MidiMessage::Clock));
+            assert_eq!(transport.sent.pop(), None);
+        }
+    }
+
+    #[test]
+    fn router_keeps_sending_when_one_transport_is_full() {
+        let full = MockTransport { sent: Queue::new() };
+        while full.sent.push(MidiMessage::Clock) {}
+        let other = MockTransport { sent: Queue::new() };
+        let outputs: [&dyn MidiTransport; 2] = [&full, &other];
+        let router = Router::new(&outputs);
+        assert!(!router.send(MidiMessage::Start));
+        assert_eq!(other.sent.pop(), Some(MidiMessage::Start));
+    }
+
+    #[test]
+    fn routes() {
+        assert_eq!(route(&MidiMessage::Clock), Route::Clock);
+        assert_eq!(route(&MidiMessage::SongPosition(4)), Route::Transport);
+        assert_eq!(route(&MidiMessage::Stop), Route::Transport);
+        let cc = MidiMessage::ControlChange { channel: 0, control: 1, value: 2 };
+        assert_eq!(route(&cc), Route::MainLoop);
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            MidiMessage::NoteOn { channel: 3, note: 64, velocity: 1 },
+            MidiMessage::ControlChange { channel: 15, control: 74, value: 127 },
+            MidiMessage::ProgramChange { channel: 0, program: 15 },
+            MidiMessage::SongPosition(0x3FFF),
+            MidiMessage::Stop,
+        ];
+        let mut parser = Parser::new();
+        for message in messages {
+            let (bytes, len) = message.to_bytes();
+            let mut parsed = None;
+            for &byte in &bytes[..len] {
+                parsed = parser.feed(byte).or(parsed);
+            }
+            assert_eq!(parsed, Some(message));
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::clock::{clock_source, midi_clock_pulse, ClockSource};
+use crate::midi::{route, MidiMessage, MidiTransport, Queue, Route, Router};
+use crate::midi_uart::DIN;
+
+const RX_QUEUE_LEN: usize = 32;
+
+static OUTPUTS: [&dyn MidiTransport; 1] = [&DIN];
+static ROUTER: Router = Router::new(&OUTPUTS);
+// Filled from the USART6 receive interrupt.
+static RX_QUEUE: Queue<MidiMessage, RX_QUEUE_LEN> = Queue::new();
+
+/// Send a message on every MIDI output. Safe to call from the timer ISR.
+pub fn send(message: MidiMessage) {
+    ROUTER.send(message);
+}
+
+/// Next received note, CC, program change or transport message. Clock pulses are handled in
+/// the interrupt and never show up here, transport only while following MIDI clock.
+pub fn receive() -> Option<MidiMessage> {
+    RX_QUEUE.pop()
+}
+
+/// Called by the transports for every received message.
+pub(crate) fn on_message(message: MidiMessage) {
+    match route(&message) {
+        Route::Clock => midi_clock_pulse(),
+        Route::Transport => {
+            // Only follow the transport of the device we take our clock from.
+            if clock_source() == ClockSource::Midi {
+                RX_QUEUE.push(message);
+            }
+        }
+        Route::MainLoop => {
+            RX_QUEUE.push(message);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use stm32f4xx_hal::pac::{self, USART6};
+use stm32f4xx_hal::{interrupt, rcc::Clocks};
+
+use crate::midi::{MidiMessage, MidiTransport, Parser, Queue};
+use crate::midi_io;
+
+const MIDI_BAUD: u32 = 31_250;
+const TX_QUEUE_LEN: usize = 64;
+
+static TX_QUEUE: Queue<MidiMessage, TX_QUEUE_LEN> = Queue::new();
+// Message currently being shifted out and the index of its next byte.
+static mut TX_BYTES: [u8; 3] = [0; 3];
+static mut TX_LEN: usize = 0;
+static mut TX_POS: usize = 0;
+
+static mut PARSER: Parser = Parser::new();
+
+/// DIN MIDI on USART6, TX on PC6 and RX on PC7. The USART6 interrupt should run at a lower
+/// priority than TIM3 so sending never delays the step timer.
+pub fn init_midi_uart(usart6: USART6, clocks: &Clocks) {
+    unsafe {
+        let rcc = &*pac::RCC::ptr();
+        rcc.apb2enr().modify(|_, w| w.usart6en().set_bit());
+        rcc.apb2rstr().modify(|_, w| w.usart6rst().set_bit());
+        rcc.apb2rstr().modify(|_, w| w.usart6rst().clear_bit());
+    }
+
+    let pclk = clocks.pclk2().raw();
+    let div = (pclk + MIDI_BAUD / 2) / MIDI_BAUD;
+    usart6.brr().write(|w| unsafe { w.bits(div) });
+    usart6.cr1().write(|w| w.ue().set_bit().te().set_bit().re().set_bit().rxneie().set_bit());
+}
+
+/// The DIN output. Sending only copies the message and enables the TX interrupt.
+pub struct DinTransport;
+
+pub static DIN: DinTransport = DinTransport;
+
+impl MidiTransport for DinTransport {
+    fn send(&self, message: MidiMessage) -> bool {
+        if !TX_QUEUE.push(message) {
+            return false;
+        }
+        let usart6 = unsafe { &*pac::USART6::ptr() };
+        usart6.cr1().modify(|_, w| w.txeie().set_bit());
+        true
+    }
+}
+
+#[interrupt]
+fn USART6() {
+    let usart6 = unsafe { &*pac::USART6::ptr() };
+    let sr = usart6.sr().read();
+    // Reading DR after SR also clears an overrun, the lost byte just resyncs the parser.
+    if sr.rxne().bit_is_set() || sr.ore().bit_is_set() {
+        let byte = usart6.dr().read().dr().bits() as u8;
+        let parser = &raw mut PARSER;
+        if let Some(message) = unsafe { (*parser).feed(byte) } {
+            midi_io::on_message(message);
+        }
+    }
+    if sr.txe().bit_is_set() && usart6.cr1().read().txeie().bit_is_set() {
+        unsafe { send_next_byte(usart6) };
+    }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn send_next_byte(usart6: &pac::usart1::RegisterBlock) {
+    if TX_POS >= TX_LEN {
+        match TX_QUEUE.pop() {
+            Some(message) => {
+                let (bytes, len) = message.to_bytes();
+                TX_BYTES = bytes;
+                TX_LEN = len;
+                TX_POS = 0;
+            }
+            None => {
+                usart6.cr1().modify(|_, w| w.txeie().clear_bit());
+                // `send()` may have preempted us between the pop and disabling the
+                // interrupt, don't strand its message.
+                if !TX_QUEUE.is_empty() {
+                    usart6.cr1().modify(|_, w| w.txeie().set_bit());
+                }
+                return;
+            }
+        }
+    }
+    usart6.dr().write(|w| w.dr().bits(TX_BYTES[TX_POS] as u16));
+    TX_POS += 1;
+}
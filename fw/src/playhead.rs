@@ -0,0 +1,458 @@
+use crate::sequencer::{MAX_STEPS, MAX_TRACKS};
+
+/// Lengths a roll can loop, in steps.
+pub const ROLL_LENGTHS: [u8; 4] = [1, 2, 4, 8];
+
+/// Beat repeat. While on, the step heard loops over the steps that played just before the roll
+/// started, while the pattern position keeps moving underneath. Turning it off goes back to
+/// that position, as if the roll never happened.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Roll {
+    len: u8,
+    start: u8,
+    count: u8,
+}
+
+impl Roll {
+    pub const fn new() -> Self {
+        Self { len: 0, start: 0, count: 0 }
+    }
+
+    /// Loop length in steps, 0 when off.
+    pub fn steps(&self) -> u8 {
+        self.len
+    }
+
+    /// Loops the `len` steps before `step`, the one about to play. `len` is cut at the
+    /// pattern `length`, 0 turns the roll off.
+    pub fn start(&mut self, len: u8, step: u8, length: u8) {
+        let length = length.max(1);
+        let len = len.min(length);
+        self.len = len;
+        self.start = (step + length - len) % length;
+        self.count = 0;
+    }
+
+    pub fn stop(&mut self) {
+        self.len = 0;
+    }
+
+    /// Returns the step heard when the pattern position is `step`, and moves on to the next.
+    pub fn audible_step(&mut self, step: u8, length: u8) -> u8 {
+        if self.len == 0 {
+            return step;
+        }
+        let audible = (self.start + self.count) % length.max(1);
+        self.count = (self.count + 1) % self.len;
+        audible
+    }
+}
+
+impl Default for Roll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Steps `start..=end` a track loops over.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Window {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl Window {
+    /// Every step of a full length track.
+    pub const ALL: Self = Self { start: 0, end: MAX_STEPS as u8 - 1 };
+
+    /// Window of a track of `length` steps that loops `loop_start..=loop_end`. Both are cut
+    /// at the track length, an end before the start plays just the start.
+    pub fn new(loop_start: u8, loop_end: u8, length: u8) -> Self {
+        let last = length.clamp(1, MAX_STEPS as u8) - 1;
+        let start = loop_start.min(last);
+        Self { start, end: loop_end.clamp(start, last) }
+    }
+
+    pub fn steps(&self) -> u8 {
+        self.end - self.start + 1
+    }
+
+    /// Step played `position` steps after a track starting at step `offset` was reset, moved
+    /// `back` steps earlier by a roll. An offset outside the window starts at its nearest end.
+    pub fn step_at(&self, offset: u8, position: u32, back: u8) -> u8 {
+        let first = offset.clamp(self.start, self.end) - self.start;
+        let index = (first as i64 + position as i64 - back as i64).rem_euclid(self.steps() as i64);
+        self.start + index as u8
+    }
+
+    /// Step played after `step`.
+    pub fn next(&self, step: u8) -> u8 {
+        if step >= self.end || step < self.start { self.start } else { step + 1 }
+    }
+
+    /// True unless the window leaves out some of the steps up to `length`.
+    pub fn is_whole(&self, length: u8) -> bool {
+        self.start == 0 && self.end as usize + 1 >= (length as usize).clamp(1, MAX_STEPS)
+    }
+}
+
+/// When a track goes back to its start step.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetMode {
+    /// Follows the pattern position, so it restarts whenever the pattern does.
+    PatternStart,
+    /// Counts on across pattern switches, but follows a Song Position Pointer from the MIDI
+    /// clock leader and starts over on a pulse at the reset input.
+    External,
+    /// Counts on across pattern switches and jumps, only a fresh start resets it.
+    Never,
+}
+
+impl ResetMode {
+    pub fn next(self) -> Self {
+        match self {
+            ResetMode::PatternStart => ResetMode::External,
+            ResetMode::External => ResetMode::Never,
+            ResetMode::Never => ResetMode::PatternStart,
+        }
+    }
+}
+
+/// Something that moves tracks back to their start.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reset {
+    /// Playback starts from the top, after a stop or on MIDI Start.
+    Start,
+    /// A pulse at the reset input, the next step starts over.
+    Input,
+    /// The clock leader sent a Song Position Pointer, the pattern continues from this step.
+    SongPosition(u8),
+    /// The song position was moved here, the pattern continues from this step.
+    Jump(u8),
+}
+
+/// What the transport did, for the rules in `Transport::reset()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transport {
+    /// Playback starts at song position `from`. Stop rewinds to 0 first, pause doesn't.
+    Play { from: u16 },
+    /// The song position was moved to where the pattern plays `step`, from the panel or by
+    /// stopping.
+    Jump(u8),
+    /// The clock leader sent a Song Position Pointer that lands on `step`.
+    SongPosition(u8),
+    /// A new pattern starts from its first step.
+    PatternSwitch,
+    /// A pulse came in at the reset input.
+    ResetInput,
+}
+
+impl Transport {
+    /// How the tracks are reset, if at all. Playing from the top starts every track over,
+    /// continuing from anywhere else keeps them where they are.
+    pub fn reset(self) -> Option<Reset> {
+        match self {
+            Transport::Play { from: 0 } => Some(Reset::Start),
+            Transport::Play { .. } => None,
+            Transport::Jump(step) => Some(Reset::Jump(step)),
+            Transport::SongPosition(step) => Some(Reset::SongPosition(step)),
+            Transport::PatternSwitch => Some(Reset::Jump(0)),
+            Transport::ResetInput => Some(Reset::Input),
+        }
+    }
+}
+
+/// How far each track is from its start, in steps. A track at position 0 plays its offset.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Positions {
+    positions: [u32; MAX_TRACKS],
+}
+
+impl Positions {
+    pub const fn new() -> Self {
+        Self { positions: [0; MAX_TRACKS] }
+    }
+
+    pub fn get(&self, track_index: usize) -> u32 {
+        self.positions[track_index]
+    }
+
+    /// Moves the tracks that `reset` applies to in their mode.
+    pub fn reset(&mut self, modes: &[ResetMode; MAX_TRACKS], reset: Reset) {
+        for (position, &mode) in self.positions.iter_mut().zip(modes) {
+            let to = match (mode, reset) {
+                (_, Reset::Start) => 0,
+                (ResetMode::PatternStart, Reset::SongPosition(step) | Reset::Jump(step)) => step,
+                (ResetMode::External, Reset::SongPosition(step)) => step,
+                (ResetMode::External, Reset::Input) => 0,
+                _ => continue,
+            };
+            *position = to as u32;
+        }
+    }
+
+    /// Moves every track on by `steps` while the pattern moves on to `step`. Tracks that
+    /// reset with the pattern just follow it there.
+    pub fn advance(&mut self, modes: &[ResetMode; MAX_TRACKS], steps: u8, step: u8) {
+        for (position, &mode) in self.positions.iter_mut().zip(modes) {
+            *position = match mode {
+                ResetMode::PatternStart => step as u32,
+                ResetMode::External | ResetMode::Never => position.wrapping_add(steps as u32),
+            };
+        }
+    }
+}
+
+impl Default for Positions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plays `count` steps from pattern position `step`, returns the steps heard and where the
+    // pattern position ends up.
+    fn play(roll: &mut Roll, step: &mut u8, length: u8, count: usize) -> [u8; 16] {
+        let mut heard = [0xFF; 16];
+        for slot in heard.iter_mut().take(count) {
+            *slot = roll.audible_step(*step, length);
+            *step = (*step + 1) % length;
+        }
+        heard
+    }
+
+    #[test]
+    fn off_plays_the_position() {
+        let mut roll = Roll::new();
+        let mut step = 3;
+        let heard = play(&mut roll, &mut step, 16, 3);
+        assert_eq!(&heard[..3], &[3, 4, 5]);
+        assert_eq!(step, 6);
+    }
+
+    #[test]
+    fn loops_the_last_steps() {
+        let mut roll = Roll::new();
+        let mut step = 6;
+        roll.start(4, step, 16);
+        let heard = play(&mut roll, &mut step, 16, 7);
+        assert_eq!(&heard[..7], &[2, 3, 4, 5, 2, 3, 4]);
+    }
+
+    #[test]
+    fn resumes_where_the_pattern_would_be() {
+        let mut roll = Roll::new();
+        let mut step = 6;
+        roll.start(2, step, 16);
+        play(&mut roll, &mut step, 16, 5);
+        roll.stop();
+        let heard = play(&mut roll, &mut step, 16, 2);
+        assert_eq!(&heard[..2], &[11, 12]);
+        assert_eq!(step, 13);
+    }
+
+    #[test]
+    fn resumes_across_the_pattern_end() {
+        let mut roll = Roll::new();
+        let mut step = 6;
+        roll.start(1, step, 8);
+        let heard = play(&mut roll, &mut step, 8, 5);
+        assert_eq!(&heard[..5], &[5, 5, 5, 5, 5]);
+        roll.stop();
+        let heard = play(&mut roll, &mut step, 8, 1);
+        assert_eq!(heard[0], 3);
+    }
+
+    #[test]
+    fn wraps_the_loop_start() {
+        let mut roll = Roll::new();
+        let mut step = 1;
+        roll.start(4, step, 16);
+        let heard = play(&mut roll, &mut step, 16, 5);
+        assert_eq!(&heard[..5], &[13, 14, 15, 0, 13]);
+    }
+
+    #[test]
+    fn length_is_cut_at_the_pattern() {
+        let mut roll = Roll::new();
+        let mut step = 2;
+        roll.start(8, step, 4);
+        assert_eq!(roll.steps(), 4);
+        let heard = play(&mut roll, &mut step, 4, 5);
+        assert_eq!(&heard[..5], &[2, 3, 0, 1, 2]);
+    }
+
+    #[test]
+    fn window_is_cut_at_the_length() {
+        assert_eq!(Window::new(4, 7, 16), Window { start: 4, end: 7 });
+        assert_eq!(Window::new(0, 15, 8), Window { start: 0, end: 7 });
+        assert_eq!(Window::new(10, 15, 8), Window { start: 7, end: 7 });
+        assert_eq!(Window::new(5, 2, 16), Window { start: 5, end: 5 });
+        assert_eq!(Window::new(0, 15, 0), Window { start: 0, end: 0 });
+        assert!(Window::new(0, 15, 8).is_whole(8));
+        assert!(!Window::new(4, 7, 16).is_whole(16));
+    }
+
+    #[test]
+    fn window_loops() {
+        let window = Window::new(4, 7, 16);
+        assert_eq!(window.steps(), 4);
+        let steps = [0, 1, 3, 4, 5].map(|position| window.step_at(0, position, 0));
+        assert_eq!(steps, [4, 5, 7, 4, 5]);
+        let steps = [0, 1, 2, 3].map(|position| window.step_at(6, position, 0));
+        assert_eq!(steps, [6, 7, 4, 5]);
+        assert_eq!(window.step_at(12, 0, 0), 7);
+        // A roll steps back from the position, also past the reset.
+        assert_eq!(window.step_at(0, 5, 2), 7);
+        assert_eq!(window.step_at(0, 0, 1), 7);
+        assert_eq!(window.next(6), 7);
+        assert_eq!(window.next(7), 4);
+        assert_eq!(window.next(12), 4);
+    }
+
+    const MODES: [ResetMode; MAX_TRACKS] = [
+        ResetMode::PatternStart,
+        ResetMode::External,
+        ResetMode::Never,
+        ResetMode::PatternStart,
+        ResetMode::PatternStart,
+        ResetMode::PatternStart,
+        ResetMode::PatternStart,
+        ResetMode::PatternStart,
+    ];
+
+    // Plays `count` steps of a pattern of `length` steps from `step`, like the step timer.
+    fn run(positions: &mut Positions, step: &mut u8, length: u8, count: usize) {
+        for _ in 0..count {
+            *step = (*step + 1) % length;
+            positions.advance(&MODES, 1, *step);
+        }
+    }
+
+    fn first_three(positions: &Positions) -> [u32; 3] {
+        [positions.get(0), positions.get(1), positions.get(2)]
+    }
+
+    #[test]
+    fn play_starts_every_track_at_its_offset() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 5);
+        positions.reset(&MODES, Reset::Start);
+        assert_eq!(first_three(&positions), [0, 0, 0]);
+        let window = Window::new(0, 15, 16);
+        assert_eq!(window.step_at(3, positions.get(1), 0), 3);
+    }
+
+    #[test]
+    fn pause_keeps_positions() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 5);
+        // Nothing resets between pausing and continuing.
+        run(&mut positions, &mut step, 16, 1);
+        assert_eq!(first_three(&positions), [6, 6, 6]);
+    }
+
+    #[test]
+    fn pattern_switch_resets_only_pattern_start_tracks() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        // An 8 step pattern wraps, then a 16 step one starts in its place.
+        run(&mut positions, &mut step, 8, 8);
+        assert_eq!(step, 0);
+        assert_eq!(first_three(&positions), [0, 8, 8]);
+        run(&mut positions, &mut step, 16, 3);
+        assert_eq!(first_three(&positions), [3, 11, 11]);
+    }
+
+    #[test]
+    fn song_position_pointer_follows_the_leader() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 10);
+        positions.reset(&MODES, Reset::SongPosition(4));
+        assert_eq!(first_three(&positions), [4, 4, 10]);
+    }
+
+    #[test]
+    fn song_jump_moves_only_pattern_start_tracks() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 10);
+        positions.reset(&MODES, Reset::Jump(2));
+        assert_eq!(first_three(&positions), [2, 10, 10]);
+    }
+
+    #[test]
+    fn skipped_steps_keep_tracks_in_phase() {
+        let mut positions = Positions::new();
+        let mut step = 14;
+        positions.reset(&MODES, Reset::Jump(14));
+        step = (step + 3) % 16;
+        positions.advance(&MODES, 3, step);
+        assert_eq!(first_three(&positions), [1, 3, 3]);
+    }
+
+    // Applies what `event` does to the positions, like the sequencer does.
+    fn apply(positions: &mut Positions, event: Transport) {
+        if let Some(reset) = event.reset() {
+            positions.reset(&MODES, reset);
+        }
+    }
+
+    #[test]
+    fn play_resets_only_from_the_top() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 5);
+        apply(&mut positions, Transport::Play { from: 5 });
+        assert_eq!(first_three(&positions), [5, 5, 5]);
+        apply(&mut positions, Transport::Play { from: 0 });
+        assert_eq!(first_three(&positions), [0, 0, 0]);
+    }
+
+    #[test]
+    fn stop_then_play_starts_every_track_over() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 21);
+        // Stop rewinds the song position, which only moves the tracks following the pattern.
+        apply(&mut positions, Transport::Jump(0));
+        assert_eq!(first_three(&positions), [0, 21, 21]);
+        apply(&mut positions, Transport::Play { from: 0 });
+        assert_eq!(first_three(&positions), [0, 0, 0]);
+    }
+
+    #[test]
+    fn transport_moves_follow_the_modes() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 10);
+        apply(&mut positions, Transport::Jump(6));
+        assert_eq!(first_three(&positions), [6, 10, 10]);
+        apply(&mut positions, Transport::SongPosition(3));
+        assert_eq!(first_three(&positions), [3, 3, 10]);
+        step = 3;
+        run(&mut positions, &mut step, 16, 2);
+        apply(&mut positions, Transport::PatternSwitch);
+        assert_eq!(first_three(&positions), [0, 5, 12]);
+    }
+
+    #[test]
+    fn reset_input_restarts_only_external_tracks() {
+        let mut positions = Positions::new();
+        let mut step = 0;
+        run(&mut positions, &mut step, 16, 7);
+        apply(&mut positions, Transport::ResetInput);
+        assert_eq!(first_three(&positions), [7, 0, 7]);
+        // The next step plays the track's offset, then it counts on from there.
+        let window = Window::new(0, 15, 16);
+        assert_eq!(window.step_at(2, positions.get(1), 0), 2);
+        run(&mut positions, &mut step, 16, 12);
+        assert_eq!(first_three(&positions), [3, 12, 19]);
+    }
+}
@@ -3,7 +3,7 @@ use core::sync::atomic::Ordering;
 
 use embedded_hal::digital::OutputPin;
 
-use crate::sequencer::{BPM, SequencerState};
+use crate::sequencer::{BPM, RecordMode, SequencerState};
 use crate::utils::{FmtBuf, iter_bits_u8};
 
 const COLOR_BG: u32 = 0x000000;
@@ -13,13 +13,20 @@ const COLOR_SIDEBAR_BG: u32 = 0x000000;
 // const COLOR_GRID_FG: u32 = 0x222222;
 const COLOR_CELL_BG: u32 = 0x121212;
 const COLOR_CELL_SECONDARY_BG: u32 = 0x000000;
+const COLOR_CELL_TRACK_SELECTED_BG: u32 = 0x1A2A1A;
+const COLOR_CELL_TRACK_SELECTED_SECONDARY_BG: u32 = 0x0A1A0A;
 // const COLOR_CELL_SELECTED_BG: u32 = 0x05b669;
 const COLOR_CELL_SELECTED_BG: u32 = 0x3F9834;
+const COLOR_CELL_SOLO_FG: u32 = 0xB8A848;
 const COLOR_PLAYHEAD_FG: u32 = 0xF07826;
+const COLOR_LOOP_FG: u32 = 0x3A78C0;
 const COLOR_TRACK_LABEL_FG: u32 = COLOR_GRID_FG;
 const COLOR_TRACK_LABEL_ACTIVE_FG: u32 = 0xF07826;
+const COLOR_TRACK_LABEL_MUTED_FG: u32 = 0x333333;
+const COLOR_TRACK_LABEL_SOLO_FG: u32 = 0xF0D026;
 
 const COLOR_ACCENT_BG: u32 = 0x134213;
+const COLOR_RECORD_BG: u32 = 0xC02020;
 
 const SCREEN_W: u16 = 1024;
 const SCREEN_H: u16 = 600;
@@ -44,7 +51,7 @@ const BOTTOM_GAP: u16 = 4;
 
 const PATTERN_AREA_X: u16 = 12;
 const PATTERN_AREA_Y: u16 = SCREEN_H - BOTTOM_H;
-const PATTERN_AREA_W: u16 = 28;
+const PATTERN_AREA_W: u16 = 56; // Room for "shown/edited" pattern numbers.
 const PATTERN_TEXT_H: u16 = 16;
 const PATTERN_TEXT_X: u16 = PATTERN_AREA_X + 8;
 const PATTERN_TEXT_Y: u16 = PATTERN_AREA_Y + (BOTTOM_H / 2) - (PATTERN_TEXT_H / 2);
@@ -55,6 +62,12 @@ const BPM_AREA_W: u16 = 28;
 const BPM_TEXT_H: u16 = 16;
 const BPM_TEXT_X: u16 = BPM_AREA_X + 10;
 const BPM_TEXT_Y: u16 = BPM_AREA_Y + (BOTTOM_H / 2) - (BPM_TEXT_H / 2);
+const RECORD_AREA_X: u16 = BPM_AREA_X + 96;
+const RECORD_AREA_Y: u16 = PATTERN_AREA_Y;
+const RECORD_AREA_W: u16 = 72;
+const RECORD_TEXT_H: u16 = 16;
+const RECORD_TEXT_X: u16 = RECORD_AREA_X + 8;
+const RECORD_TEXT_Y: u16 = RECORD_AREA_Y + (BOTTOM_H / 2) - (RECORD_TEXT_H / 2);
 const LABEL_X: u16 = 22;
 
 pub fn render<I: lt7683::LT7683Interface, RESET: OutputPin>(
@@ -64,11 +77,12 @@ pub fn render<I: lt7683::LT7683Interface, RESET: OutputPin>(
     render_frame(display);
     render_pattern_indicator(display, sequencer_state);
     render_bpm(display);
+    render_record_indicator(display, sequencer_state);
     for track_index in iter_bits_u8(sequencer_state.get_all_tracks()) {
         let y1 = GRID_TOP + (track_index as u16) * ROW_HEIGHT;
         let y2 = y1 + ROW_HEIGHT;
         let _ = display.draw_rectangle(GRID_LEFT, y1, GRID_RIGHT, y2, COLOR_GRID_FG, false);
-        render_track_label(display, track_index, sequencer_state.is_track_selected(track_index));
+        render_track_label(display, sequencer_state, track_index);
     }
     for n in 1..NUM_STEPS {
         let x = GRID_LEFT + (n * CELL_WIDTH);
@@ -97,7 +111,13 @@ pub fn render_pattern_indicator<I: lt7683::LT7683Interface, RESET: OutputPin>(
 ) {
     let mut buf = [0u8; 8];
     let mut fmt = FmtBuf::new(&mut buf);
-    write!(fmt, "{:02}", sequencer_state.visible_pattern).unwrap();
+    // The pattern being edited follows when it isn't the one shown.
+    let (visible, edited) = (sequencer_state.visible_pattern, sequencer_state.edit.pattern);
+    if visible == edited {
+        write!(fmt, "{:02}", visible).unwrap();
+    } else {
+        write!(fmt, "{:02}/{:02}", visible, edited).unwrap();
+    }
     let bottom_y1 = SCREEN_H - BOTTOM_H + 6;
     let _ = display.bte_solid_fill(
         PATTERN_AREA_X,
@@ -126,12 +146,45 @@ pub fn render_bpm<I: lt7683::LT7683Interface, RESET: OutputPin>(
     let _ = display.write_text(fmt.as_str(), BPM_TEXT_X, BPM_TEXT_Y, None, COLOR_SIDEBAR_BG);
 }
 
+pub fn render_record_indicator<I: lt7683::LT7683Interface, RESET: OutputPin>(
+    display: &mut lt7683::LT7683<I, RESET>,
+    sequencer_state: &SequencerState,
+) {
+    let bottom_y1 = SCREEN_H - BOTTOM_H + 6;
+    let (text, color_bg) = match sequencer_state.record {
+        RecordMode::Off => ("", COLOR_SIDEBAR_BG),
+        RecordMode::Overdub => ("REC OVR", COLOR_RECORD_BG),
+        RecordMode::Replace => ("REC RPL", COLOR_RECORD_BG),
+        RecordMode::Step => ("REC STP", COLOR_RECORD_BG),
+    };
+    let _ = display.bte_solid_fill(
+        RECORD_AREA_X,
+        bottom_y1,
+        RECORD_AREA_W,
+        BOTTOM_LABEL_CONTAINER_HEIGHT,
+        color_bg,
+    );
+    if !text.is_empty() {
+        let _ = display.write_text(text, RECORD_TEXT_X, RECORD_TEXT_Y, None, COLOR_SIDEBAR_BG);
+    }
+}
+
 pub fn render_track_label<I: lt7683::LT7683Interface, RESET: OutputPin>(
     display: &mut lt7683::LT7683<I, RESET>,
+    sequencer_state: &SequencerState,
     track_index: u8,
-    selected: bool,
 ) {
-    let color_fg = if selected { COLOR_TRACK_LABEL_ACTIVE_FG } else { COLOR_TRACK_LABEL_FG };
+    let bit = 1 << track_index;
+    let mutes = sequencer_state.mutes();
+    let color_fg = if mutes.silenced() & bit != 0 {
+        COLOR_TRACK_LABEL_MUTED_FG
+    } else if mutes.soloed & bit != 0 {
+        COLOR_TRACK_LABEL_SOLO_FG
+    } else if sequencer_state.is_track_selected(track_index) {
+        COLOR_TRACK_LABEL_ACTIVE_FG
+    } else {
+        COLOR_TRACK_LABEL_FG
+    };
     let y = GRID_TOP + (track_index as u16) * ROW_HEIGHT;
     let text_y = y + 24;
     let _ = display.write_text(TRACK_LABELS[track_index as usize], LABEL_X, text_y, None, color_fg);
@@ -151,7 +204,13 @@ pub fn render_cell<I: lt7683::LT7683Interface, RESET: OutputPin>(
     step_index: u8,
     highlight: CellHighlight,
 ) {
-    let base_bg = if step_index % 4 == 0 { COLOR_CELL_SECONDARY_BG } else { COLOR_CELL_BG };
+    let secondary = step_index % 4 == 0;
+    let base_bg = match (sequencer_state.is_track_selected(track_index), secondary) {
+        (false, false) => COLOR_CELL_BG,
+        (false, true) => COLOR_CELL_SECONDARY_BG,
+        (true, false) => COLOR_CELL_TRACK_SELECTED_BG,
+        (true, true) => COLOR_CELL_TRACK_SELECTED_SECONDARY_BG,
+    };
     let bg_color = match highlight {
         CellHighlight::None => base_bg,
         CellHighlight::Playing => base_bg,
@@ -166,11 +225,36 @@ pub fn render_cell<I: lt7683::LT7683Interface, RESET: OutputPin>(
 
     let pattern = &sequencer_state.patterns[sequencer_state.visible_pattern as usize];
     let step = pattern.tracks[track_index as usize].steps[step_index as usize];
+    // Muted rows are dimmed and soloed ones tinted.
+    let mutes = sequencer_state.mutes();
+    let silenced = mutes.silenced() & (1 << track_index) != 0;
+    let soloed = mutes.soloed & (1 << track_index) != 0;
     let text_color = match highlight {
         CellHighlight::Selected => 0x000000,
-        _ => if step.active && step.pitch != 0 { 0x949494 } else { 0x333333 },
+        _ if !step.active || step.pitch == 0 || silenced => 0x333333,
+        _ if soloed => COLOR_CELL_SOLO_FG,
+        _ => 0x949494,
     };
     let _ = display.write_text(step.as_str(), text_x, text_y, None, text_color);
+
+    // Brackets around a loop window that leaves steps out.
+    let track = &pattern.tracks[track_index as usize];
+    let window = track.window();
+    if !window.is_whole(track.length) {
+        let (top, bottom) = (y + 4, y + ROW_HEIGHT - 4);
+        if step_index == window.start {
+            let x1 = x + 3;
+            let _ = display.draw_line(x1, top, x1, bottom, COLOR_LOOP_FG);
+            let _ = display.draw_line(x1, top, x1 + 6, top, COLOR_LOOP_FG);
+            let _ = display.draw_line(x1, bottom, x1 + 6, bottom, COLOR_LOOP_FG);
+        }
+        if step_index == window.end {
+            let x2 = x + CELL_WIDTH - 4;
+            let _ = display.draw_line(x2, top, x2, bottom, COLOR_LOOP_FG);
+            let _ = display.draw_line(x2 - 6, top, x2, top, COLOR_LOOP_FG);
+            let _ = display.draw_line(x2 - 6, bottom, x2, bottom, COLOR_LOOP_FG);
+        }
+    }
 }
 
 pub fn render_column<I: lt7683::LT7683Interface, RESET: OutputPin>(
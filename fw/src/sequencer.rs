@@ -1,8 +1,15 @@
-use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
 use stm32f4xx_hal::pac::{self, TIM3};
 use stm32f4xx_hal::{interrupt, rcc::Clocks};
 
-use crate::utils::iter_bits_u8;
+use crate::clipboard::Clipboard;
+use crate::clock;
+use crate::cv;
+use crate::midi::MidiMessage;
+use crate::midi_io;
+use crate::playhead::{Positions, ResetMode, Roll, Transport, Window, ROLL_LENGTHS};
+use crate::undo::{record_edit, History};
+use crate::utils::{iter_bits_u8, iter_bits_u16};
 
 pub static BPM: AtomicU32 = AtomicU32::new(120);
 pub static PPQN: AtomicU32 = AtomicU32::new(24);
@@ -10,6 +17,9 @@ pub static NEXT_STEP: AtomicU8 = AtomicU8::new(0);
 pub static CURRENT_STEP: AtomicU8 = AtomicU8::new(0);
 pub static STEP_FLAG: AtomicBool = AtomicBool::new(false);
 pub static PLAYING: AtomicBool = AtomicBool::new(false);
+// Set while an external clock drives the steps. `BPM` then shows the measured tempo.
+pub static EXT_CLOCK_LOCKED: AtomicBool = AtomicBool::new(false);
+static INTERNAL_BPM: AtomicU32 = AtomicU32::new(120);
 
 #[cfg(feature = "perf")]
 static OVERRUN_MISSED_STEP_SEGMENTS: AtomicU32 = AtomicU32::new(0);
@@ -18,13 +28,39 @@ static OVERRUN_MAX_US: AtomicU32 = AtomicU32::new(0);
 
 const TIMER_HZ: u32 = 1_000_000;
 const MAX_STEP_SEGMENT_US: u32 = 0xFFFF;
+const CV_TICK_US: u16 = 1000;
+// While waiting for an external clock pulse, the current step is extended in chunks of this.
+const EXT_HOLD_US: u32 = 1000;
+// Clock and reset outputs are derived from 24 PPQN ticks spread evenly over each step.
+const CLOCK_TICKS_PER_STEP: u8 = 6;
 
 pub const MAX_TRACKS: usize = 8;
 pub const MAX_STEPS: usize = 16;
 pub const MAX_PATTERNS: usize = 16;
 pub const MAX_SONG_LENGTH: usize = 64;
 pub const MAX_GATE_LENGTH: u8 = 100; // Percent of step length.
+pub const MAX_PROBABILITY: u8 = 100;
 pub const DEFAULT_GATE_LENGTH: u8 = 100; // Full step.
+pub const MIN_TRIGGER_MS: u8 = 1;
+pub const MAX_TRIGGER_MS: u8 = 10;
+pub const DEFAULT_TRIGGER_MS: u8 = 5;
+pub const LFO_RATES: [u8; 7] = [1, 2, 4, 8, 16, 32, 64]; // Cycle length in steps.
+pub const DEFAULT_LFO_RATE: u8 = 16; // One bar.
+pub const MIN_CLOCK_PULSE_MS: u8 = 1;
+pub const MAX_CLOCK_PULSE_MS: u8 = 20;
+pub const DEFAULT_MIDI_CHANNELS: [u8; MAX_TRACKS] = [0, 1, 2, 3, 4, 5, 6, 7];
+pub const MAX_VELOCITY: u8 = 127;
+pub const MAX_MICRO: u8 = 99; // Percent of the step length a gate can be delayed by.
+pub const MIN_BPM: u32 = 40;
+pub const MAX_BPM: u32 = 240;
+// Percent of a step pair given to the first step.
+pub const MIN_SWING: u8 = 50;
+pub const MAX_SWING: u8 = 75;
+pub const MAX_TRANSPOSE: i8 = 24;
+pub const MAX_CC_BINDINGS: usize = 16;
+pub const MAX_SCENES: usize = 8;
+pub const DEFAULT_VELOCITY: u8 = 100;
+const ENV_MAX_LEVEL: u32 = 1 << 24;
 
 const GATE_TRACK_INDEX: usize = 2;
 
@@ -36,6 +72,8 @@ pub const DIRTY_NOTE_DATA: u8 = 0x04;
 pub const DIRTY_BPM: u8 = 0x08;
 pub const DIRTY_PATTERN: u8 = 0x10;
 pub const DIRTY_RT_CACHE: u8 = 0x20;
+pub const DIRTY_RECORD: u8 = 0x40;
+pub const DIRTY_MUTES: u8 = 0x80;
 static DIRTY: AtomicU8 = AtomicU8::new(0);
 
 pub fn mark_dirty(flags: u8) {
@@ -46,26 +84,99 @@ pub fn take_dirty() -> u8 {
     DIRTY.swap(0, Ordering::Acquire)
 }
 
+// Grid cells to redraw, one step mask per track, on top of what the `DIRTY_*` flags imply.
+static DIRTY_CELLS: [AtomicU16; MAX_TRACKS] = [const { AtomicU16::new(0) }; MAX_TRACKS];
+
+pub fn mark_dirty_cells(tracks: u8, steps: u16) {
+    for track_index in iter_bits_u8(tracks) {
+        DIRTY_CELLS[track_index as usize].fetch_or(steps, Ordering::Release);
+    }
+}
+
+/// Marks whole step columns.
+pub fn mark_dirty_steps(steps: u16) {
+    mark_dirty_cells(0xFF, steps);
+}
+
+pub fn take_dirty_cells() -> [u16; MAX_TRACKS] {
+    core::array::from_fn(|track_index| DIRTY_CELLS[track_index].swap(0, Ordering::Acquire))
+}
+
 pub struct RtCache {
     pub gate_masks: [u16; MAX_TRACKS],
+    pub tie_masks: [u16; MAX_TRACKS],
     pub pitches: [[u8; MAX_STEPS]; MAX_TRACKS],
     pub lengths: [u8; MAX_TRACKS],
+    pub windows: [Window; MAX_TRACKS],
+    pub offsets: [u8; MAX_TRACKS],
+    pub reset_modes: [ResetMode; MAX_TRACKS],
     pub gate_lengths: [[u8; MAX_STEPS]; MAX_TRACKS],
+    pub velocities: [[u8; MAX_STEPS]; MAX_TRACKS],
+    pub micros: [[u8; MAX_STEPS]; MAX_TRACKS],
+    pub probabilities: [[u8; MAX_STEPS]; MAX_TRACKS],
+    pub output_modes: [OutputMode; MAX_TRACKS],
+    pub trigger_ms: [u8; MAX_TRACKS],
+    pub cv_modes: [CvMode; MAX_TRACKS],
+    pub lfos: [Lfo; MAX_TRACKS],
+    pub envelopes: [Envelope; MAX_TRACKS],
+    pub clock_out: ClockOutput,
+    pub reset_out: ResetOutput,
+    pub midi_channels: [u8; MAX_TRACKS],
+    pub swing: u8,
 }
 
 impl RtCache {
     pub const fn new() -> Self {
         Self {
             gate_masks: [0; MAX_TRACKS],
+            tie_masks: [0; MAX_TRACKS],
             pitches: [[0; MAX_STEPS]; MAX_TRACKS],
             lengths: [0; MAX_TRACKS],
+            windows: [Window::ALL; MAX_TRACKS],
+            offsets: [0; MAX_TRACKS],
+            reset_modes: [ResetMode::PatternStart; MAX_TRACKS],
             gate_lengths: [[0; MAX_STEPS]; MAX_TRACKS],
+            velocities: [[0; MAX_STEPS]; MAX_TRACKS],
+            micros: [[0; MAX_STEPS]; MAX_TRACKS],
+            probabilities: [[MAX_PROBABILITY; MAX_STEPS]; MAX_TRACKS],
+            output_modes: [OutputMode::Gate; MAX_TRACKS],
+            trigger_ms: [DEFAULT_TRIGGER_MS; MAX_TRACKS],
+            cv_modes: [CvMode::Pitch; MAX_TRACKS],
+            lfos: [Lfo::new(); MAX_TRACKS],
+            envelopes: [Envelope::new(); MAX_TRACKS],
+            clock_out: ClockOutput::new(),
+            reset_out: ResetOutput::new(),
+            midi_channels: DEFAULT_MIDI_CHANNELS,
+            swing: MIN_SWING,
         }
     }
 }
 
-static mut RT_CACHE: [RtCache; 2] = [RtCache::new(), RtCache::new()];
+// Banks 0-2 hold the playing pattern, three so a cache can wait for the pattern to wrap while
+// edits keep being rebuilt. Banks 3 and 4 hold the fill pattern.
+static mut RT_CACHE: [RtCache; 5] = [const { RtCache::new() }; 5];
 static ACTIVE_CACHE: AtomicU8 = AtomicU8::new(0);
+// Bank that becomes active when the next pattern starts, `NO_CACHE` if none.
+static PENDING_CACHE: AtomicU8 = AtomicU8::new(NO_CACHE);
+const NO_CACHE: u8 = 0xFF;
+const FILL_BANKS: [u8; 2] = [3, 4];
+static FILL_CACHE: AtomicU8 = AtomicU8::new(FILL_BANKS[0]);
+static FILL_HELD: AtomicBool = AtomicBool::new(false);
+static FILL_STEPS_LEFT: AtomicU8 = AtomicU8::new(0);
+// Sampled at each step start, so a step never mixes the two patterns.
+static FILL_PLAYING: AtomicBool = AtomicBool::new(false);
+// Steps the roll loops while held, 0 when released. `ROLL_REQUESTED` is what the step timer
+// last acted on.
+static ROLL_STEPS: AtomicU8 = AtomicU8::new(0);
+static mut ROLL_REQUESTED: u8 = 0;
+static mut ROLL: Roll = Roll::new();
+// Pattern position of the step playing, which a roll can make differ from `CURRENT_STEP`.
+// Clock outputs and swing follow it.
+static mut POSITION_STEP: u8 = 0;
+// Step each track plays, within its loop window.
+static mut TRACK_STEPS: [u8; MAX_TRACKS] = [0; MAX_TRACKS];
+// Where each track is for the next step, like `NEXT_STEP` is for the pattern.
+static mut POSITIONS: Positions = Positions::new();
 
 struct StepInterval {
     base_us: u32,
@@ -89,14 +200,64 @@ static mut STEP_INTERVAL: StepInterval = StepInterval::new();
 static mut REMAINING_US: u32 = 0;
 static mut LAST_CCR1: u16 = 0;
 static mut STEP_US: u32 = 0;
+static mut STEP_GATE_START_US: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
 static mut STEP_GATE_LEN_US: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
 static mut STEP_GATE_ACTIVE: [bool; MAX_TRACKS] = [false; MAX_TRACKS];
-
-#[derive(Clone, Copy, Default, Debug)]
+static mut STEP_NOMINAL_US: u32 = 0;
+static mut EXT_STEPS_ALLOWED: u8 = 0;
+static mut SEGMENT_START: u16 = 0;
+static mut SEGMENT_US: u32 = 0;
+static mut SEGMENT_ELAPSED_US: u32 = 0;
+static mut LFO_PHASE_BASE: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
+// Set with the step, so the 1 ms CV tick only multiplies. See `lfo_phase_per_us()`.
+static mut LFO_PHASE_PER_US: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
+static mut LFO_HELD: [u16; MAX_TRACKS] = [cv::CV_MID; MAX_TRACKS];
+// Steps each track's LFO has run for. Unlike the pattern step it keeps counting over the wrap,
+// so cycles longer than the pattern finish. Restarts with the song and on pattern changes.
+static mut LFO_STEPS: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
+// Set when the pending cache is a new pattern rather than an edit or a scene.
+static PATTERN_PENDING: AtomicBool = AtomicBool::new(false);
+static mut RNG_STATE: u32 = 0x2545_F491;
+static mut GATE_STATE: u8 = 0;
+static mut ENV_STAGE: [EnvStage; MAX_TRACKS] = [EnvStage::Idle; MAX_TRACKS];
+static mut ENV_LEVEL: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
+static mut ENV_PEAK: [u32; MAX_TRACKS] = [0; MAX_TRACKS];
+// Channel and note sounding on each track. The note off goes to the same channel even if the
+// track's channel changed in between.
+static mut MIDI_NOTE: [Option<(u8, u8)>; MAX_TRACKS] = [None; MAX_TRACKS];
+// Steps since the start of the song of the next step to play, sent as Song Position Pointer.
+static mut SONG_POSITION: u16 = 0;
+// Tracks the step timer keeps silent. A change waiting for the next bar or the pattern end
+// sits in QUEUED_SILENCED with one of the SILENCED_AT_* bits set, see `publish_mutes()`.
+static SILENCED_TRACKS: AtomicU8 = AtomicU8::new(0);
+static QUEUED_SILENCED: AtomicU16 = AtomicU16::new(0);
+const SILENCED_AT_BAR: u16 = 0x100;
+const SILENCED_AT_WRAP: u16 = 0x200;
+const STEPS_PER_BAR: u16 = 16;
+static mut CLOCK_TICK_IN_STEP: u8 = 0;
+// Clock ticks follow the unswung step grid. The two steps of a swung pair share one run of
+// ticks, counted from the start of the first step, and CLOCK_PAIR_US is how far into that run
+// the second step starts. CLOCK_POSITION is the song position in ticks of the run's first
+// tick, so pulses slower than a step stay on the beat across pattern ends of any length.
+static mut CLOCK_STEP_US: u32 = 0;
+static mut CLOCK_PAIR_US: u32 = 0;
+static mut CLOCK_TICKS_END: u8 = CLOCK_TICKS_PER_STEP;
+static mut CLOCK_POSITION: u32 = 0;
+static mut CLOCK_OUT_END_US: u32 = 0;
+static mut RESET_OUT_END_US: u32 = 0;
+// The step playing started the song or a new pattern, so the reset output pulses with it.
+static mut RESET_OUT_DUE: bool = false;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
 pub struct Step {
     pub active: bool,
     pub pitch: u8,
     pub gate_len: u8,
+    pub velocity: u8,
+    pub micro: u8, // Gate delay, percent of step length.
+    pub probability: u8, // Percent chance of the step playing.
+    // Holds the previous step's note through this step instead of playing a new one.
+    pub tie: bool,
 }
 
 impl Step {
@@ -105,11 +266,16 @@ impl Step {
             active: false,
             pitch: 0,
             gate_len: DEFAULT_GATE_LENGTH,
+            velocity: DEFAULT_VELOCITY,
+            micro: 0,
+            probability: MAX_PROBABILITY,
+            tie: false,
         }
     }
 
     pub fn as_str(&self) -> &'static str {
         if !self.active { return "--"; }
+        if self.tie { return "__"; }
         match self.pitch {
             60 => "C4",
             61 => "C#4",
@@ -119,10 +285,150 @@ impl Step {
     }
 }
 
+/// What the gate output of a track does on each step.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputMode {
+    /// Gate stays high for `gate_len` percent of the step.
+    Gate,
+    /// Fixed `trigger_ms` pulse on active steps, independent of tempo.
+    Trigger,
+    /// Fixed `trigger_ms` pulse on every step, active or not.
+    Clock,
+}
+
+impl OutputMode {
+    pub fn next(self) -> Self {
+        match self {
+            OutputMode::Gate => OutputMode::Trigger,
+            OutputMode::Trigger => OutputMode::Clock,
+            OutputMode::Clock => OutputMode::Gate,
+        }
+    }
+}
+
+/// What the CV output of a track does.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CvMode {
+    /// Step pitches, 1 V/oct.
+    Pitch,
+    /// Tempo-synced LFO, see `Track::lfo`.
+    Lfo,
+    /// Envelope triggered by the track's steps, see `Track::envelope`.
+    Envelope,
+}
+
+impl CvMode {
+    pub fn next(self) -> Self {
+        match self {
+            CvMode::Pitch => CvMode::Lfo,
+            CvMode::Lfo => CvMode::Envelope,
+            CvMode::Envelope => CvMode::Pitch,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleHold,
+}
+
+impl LfoShape {
+    pub fn next(self) -> Self {
+        match self {
+            LfoShape::Sine => LfoShape::Triangle,
+            LfoShape::Triangle => LfoShape::Saw,
+            LfoShape::Saw => LfoShape::Square,
+            LfoShape::Square => LfoShape::SampleHold,
+            LfoShape::SampleHold => LfoShape::Sine,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Lfo {
+    pub shape: LfoShape,
+    // Cycle length in steps, 16 steps per bar. Phase resets on pattern start.
+    pub rate: u8,
+}
+
+impl Lfo {
+    pub const fn new() -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            rate: DEFAULT_LFO_RATE,
+        }
+    }
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EnvelopeMode {
+    /// Holds at sustain while the gate is high, releases when it ends.
+    Adsr,
+    /// Decays to zero right after the attack, ignoring the gate length.
+    Ad,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Envelope {
+    pub mode: EnvelopeMode,
+    pub attack_ms: u16,
+    pub decay_ms: u16,
+    pub sustain: u8, // Percent of the peak level.
+    pub release_ms: u16,
+}
+
+impl Envelope {
+    pub const fn new() -> Self {
+        Self {
+            mode: EnvelopeMode::Adsr,
+            attack_ms: 5,
+            decay_ms: 200,
+            sustain: 60,
+            release_ms: 300,
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EnvStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
 #[derive(Clone, Copy)]
 pub struct Track {
     pub steps: [Step; MAX_STEPS],
     pub length: u8,
+    // Steps played, `loop_start..=loop_end`, cut at `length`. The rest are kept but skipped.
+    pub loop_start: u8,
+    pub loop_end: u8,
+    // Step the track starts from after a reset, see `ResetMode` for when that is.
+    pub offset: u8,
+    pub reset_mode: ResetMode,
+    pub output_mode: OutputMode,
+    pub trigger_ms: u8,
+    pub cv_mode: CvMode,
+    pub lfo: Lfo,
+    pub envelope: Envelope,
 }
 
 impl Track {
@@ -130,8 +436,21 @@ impl Track {
         Self {
             steps: [Step::new(); MAX_STEPS],
             length: MAX_STEPS as u8,
+            loop_start: 0,
+            loop_end: MAX_STEPS as u8 - 1,
+            offset: 0,
+            reset_mode: ResetMode::PatternStart,
+            output_mode: OutputMode::Gate,
+            trigger_ms: DEFAULT_TRIGGER_MS,
+            cv_mode: CvMode::Pitch,
+            lfo: Lfo::new(),
+            envelope: Envelope::new(),
         }
     }
+
+    pub fn window(&self) -> Window {
+        Window::new(self.loop_start, self.loop_end, self.length)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -147,17 +466,70 @@ impl Song {
             length: 0,
         }
     }
+
+    /// Entry that song step `position` falls in and the step within its pattern, with the song
+    /// looping. `length_of` gives a pattern's length. None for an empty song.
+    pub fn locate(&self, position: u16, length_of: impl Fn(u8) -> u8) -> Option<(u8, u8)> {
+        let entries = &self.entries[..(self.length as usize).min(MAX_SONG_LENGTH)];
+        let total: u32 = entries.iter().map(|&pattern| length_of(pattern).max(1) as u32).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut position = position as u32 % total;
+        for (index, &pattern) in entries.iter().enumerate() {
+            let length = length_of(pattern).max(1) as u32;
+            if position < length {
+                return Some((index as u8, position as u8));
+            }
+            position -= length;
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Mutes {
+    pub muted: u8,
+    pub soloed: u8,
+}
+
+impl Mutes {
+    pub const fn new() -> Self {
+        Self { muted: 0, soloed: 0 }
+    }
+
+    /// Tracks that don't play: the muted ones, and while any track is soloed, all others.
+    pub fn silenced(self) -> u8 {
+        if self.soloed != 0 { self.muted | !self.soloed } else { self.muted }
+    }
+}
+
+/// A saved performance setup, recalled with one button.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Scene {
+    pub mutes: Mutes,
+    pub transpose: i8,
+}
+
+/// Where mute and solo state is kept, see `SequencerState::mutes()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MuteScope {
+    Global,
+    /// Each pattern keeps its own, and they change along with the playing pattern.
+    Pattern,
 }
 
 #[derive(Clone, Copy)]
 pub struct Pattern {
     pub tracks: [Track; MAX_TRACKS],
+    pub mutes: Mutes,
 }
 
 impl Pattern {
     pub const fn new() -> Self {
         Self {
             tracks: [Track::new(); MAX_TRACKS],
+            mutes: Mutes::new(),
         }
     }
 
@@ -170,27 +542,298 @@ impl Pattern {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClockOutRate {
+    Ppqn1,
+    Ppqn4,
+    Ppqn8,
+    Ppqn24,
+    /// One pulse per step. Same as 4 PPQN while steps are 16ths.
+    Step,
+}
+
+impl ClockOutRate {
+    pub fn next(self) -> Self {
+        match self {
+            ClockOutRate::Ppqn1 => ClockOutRate::Ppqn4,
+            ClockOutRate::Ppqn4 => ClockOutRate::Ppqn8,
+            ClockOutRate::Ppqn8 => ClockOutRate::Ppqn24,
+            ClockOutRate::Ppqn24 => ClockOutRate::Step,
+            ClockOutRate::Step => ClockOutRate::Ppqn1,
+        }
+    }
+
+    /// Number of 24 PPQN ticks between pulses.
+    fn ticks_per_pulse(self) -> u32 {
+        let ppqn = match self {
+            ClockOutRate::Ppqn1 => 1,
+            ClockOutRate::Ppqn4 => 4,
+            ClockOutRate::Ppqn8 => 8,
+            ClockOutRate::Ppqn24 => 24,
+            ClockOutRate::Step => return CLOCK_TICKS_PER_STEP as u32,
+        };
+        let pulses_per_step = pulses_per_step_from_ppqn(ppqn).unwrap_or(1);
+        // 1 PPQN has no whole number of pulses per step.
+        if ppqn < 4 { 24 / ppqn } else { CLOCK_TICKS_PER_STEP as u32 / pulses_per_step }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClockOutput {
+    pub rate: ClockOutRate,
+    pub pulse_ms: u8,
+    // Idle high, pulse low.
+    pub inverted: bool,
+}
+
+impl ClockOutput {
+    pub const fn new() -> Self {
+        Self {
+            rate: ClockOutRate::Ppqn4,
+            pulse_ms: 5,
+            inverted: false,
+        }
+    }
+}
+
+impl Default for ClockOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pulses when playback starts from the top of the song and when a new pattern starts, not on
+/// every pattern wrap.
+#[derive(Clone, Copy, Debug)]
+pub struct ResetOutput {
+    pub pulse_ms: u8,
+    // Idle high, pulse low.
+    pub inverted: bool,
+}
+
+impl ResetOutput {
+    pub const fn new() -> Self {
+        Self {
+            pulse_ms: 5,
+            inverted: false,
+        }
+    }
+}
+
+impl Default for ResetOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global settings that aren't part of the pattern data.
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub clock_out: ClockOutput,
+    pub reset_out: ResetOutput,
+    // MIDI channel (0-15) each track sends its notes on.
+    pub midi_channels: [u8; MAX_TRACKS],
+    pub swing: u8,
+    pub transpose: i8, // Semitones, applied to all tracks.
+    pub cc_bindings: [Option<CcBinding>; MAX_CC_BINDINGS],
+    pub mute_scope: MuteScope,
+    // Mute and solo changes wait for the next bar.
+    pub quantize_mutes: bool,
+    // Scene recalls wait for the end of the playing pattern.
+    pub quantize_scenes: bool,
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            clock_out: ClockOutput::new(),
+            reset_out: ResetOutput::new(),
+            midi_channels: DEFAULT_MIDI_CHANNELS,
+            swing: MIN_SWING,
+            transpose: 0,
+            cc_bindings: [None; MAX_CC_BINDINGS],
+            mute_scope: MuteScope::Global,
+            quantize_mutes: false,
+            quantize_scenes: false,
+        }
+    }
+}
+
+/// Something a MIDI CC can control. Track parameters apply to the selected tracks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Param {
+    Bpm,
+    Swing,
+    Transpose,
+    Mute(u8),
+    TriggerLength,
+    LfoRate,
+    EnvAttack,
+    EnvDecay,
+    EnvSustain,
+    EnvRelease,
+    EnvMode,
+    MidiChannel,
+    ClockOutPulse,
+    ClockOutInverted,
+    ResetOutPulse,
+    ResetOutInverted,
+}
+
+impl Param {
+    pub fn next(self) -> Self {
+        match self {
+            Param::Bpm => Param::Swing,
+            Param::Swing => Param::Transpose,
+            Param::Transpose => Param::Mute(0),
+            Param::Mute(track) if (track as usize) < MAX_TRACKS - 1 => Param::Mute(track + 1),
+            Param::Mute(_) => Param::TriggerLength,
+            Param::TriggerLength => Param::LfoRate,
+            Param::LfoRate => Param::EnvAttack,
+            Param::EnvAttack => Param::EnvDecay,
+            Param::EnvDecay => Param::EnvSustain,
+            Param::EnvSustain => Param::EnvRelease,
+            Param::EnvRelease => Param::EnvMode,
+            Param::EnvMode => Param::MidiChannel,
+            Param::MidiChannel => Param::ClockOutPulse,
+            Param::ClockOutPulse => Param::ClockOutInverted,
+            Param::ClockOutInverted => Param::ResetOutPulse,
+            Param::ResetOutPulse => Param::ResetOutInverted,
+            Param::ResetOutInverted => Param::Bpm,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CcBinding {
+    pub channel: u8,
+    pub control: u8,
+    pub param: Param,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pattern, tracks and steps that edits apply to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EditContext {
+    pub pattern: u8,
+    pub tracks: u8,
+    pub steps: u16,
+}
+
+impl EditContext {
+    pub const fn new() -> Self {
+        Self {
+            pattern: 0,
+            tracks: 1,
+            steps: 0,
+        }
+    }
+}
+
+impl Default for EditContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct SequencerState {
     pub max_steps: u8,
     pub patterns: [Pattern; MAX_PATTERNS],
     pub song: Song,
+    pub scenes: [Option<Scene>; MAX_SCENES],
+    // Pattern played in place of the playing one during a fill.
+    pub fill_pattern: Option<u8>,
+    pub settings: Settings,
 
     pub play_mode: PlayMode,
     pub song_position: u8,
     pub step_position: u8,
 
+    // Pattern shown on the grid. See `update_view()`.
     pub visible_pattern: u8,
     pub playing_pattern: u8,
-    pub selected_tracks: u8,
-
-    // NOTE: By storing the selected step like this, we are basically restricting ourselves to
-    // being able to edit only the steps that are currently visible (visible_pattern). So if there
-    // are multiple patterns, once the current pattern changes, we need to either set the
-    // selected_step to None, or keep it as is, which means that user would be editing step from
-    // the next pattern. tldr: for now, no way to edit step of a pattern that is not visible
-    // anymore.
+    // What edits apply to, independent of what is shown or playing.
+    pub edit: EditContext,
+    // The view follows the playing pattern instead of staying on `edit.pattern`.
+    pub follow: bool,
+
+    // Step that single step edits go to, such as step record. It is one of `edit.steps`.
     pub selected_step: Option<u8>,
-    pub prev_selected_step: Option<u8>,
+    // Step button held down. Pressing another step selects the range between them.
+    pub held_step: Option<u8>,
+    // The held step anchored a range, so releasing it doesn't toggle it.
+    pub held_step_used: bool,
+
+    // While recording, notes and hits go to the playhead instead of the selected step.
+    pub record: RecordMode,
+    // Keep how far off the grid a recorded hit was in the step's microtiming, instead of
+    // moving it to the nearest step.
+    pub record_micro: bool,
+    // Steps of each track written during the current pass, which replace mode doesn't clear.
+    pub recorded_steps: [u16; MAX_TRACKS],
+    // Used with `MuteScope::Global`.
+    pub mutes: Mutes,
+    // Starts playing when the current pattern wraps.
+    pub queued_pattern: Option<u8>,
+    // Parameter shown for editing, and the one MIDI learn binds to.
+    pub param_cursor: Param,
+    pub learn: bool,
+    // Shift button held, track buttons then add to the selection instead of replacing it.
+    pub shift_held: bool,
+    // Steps the roll button loops, one of `ROLL_LENGTHS`.
+    pub roll_steps: u8,
+    pub history: History,
+    pub clipboard: Clipboard,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecordMode {
+    Off,
+    /// Adds to what's there.
+    Overdub,
+    /// Clears the steps of the selected tracks as the playhead passes them.
+    Replace,
+    /// Notes go to the selected step, which then moves on to the next step.
+    Step,
+}
+
+impl RecordMode {
+    pub fn next(self) -> Self {
+        match self {
+            RecordMode::Off => RecordMode::Overdub,
+            RecordMode::Overdub => RecordMode::Replace,
+            RecordMode::Replace => RecordMode::Step,
+            RecordMode::Step => RecordMode::Off,
+        }
+    }
+
+    /// Overdub and replace record at the playhead while playing.
+    pub fn is_live(self) -> bool {
+        matches!(self, RecordMode::Overdub | RecordMode::Replace)
+    }
+}
+
+/// Step values that can be set on a whole selection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StepField {
+    GateLength,
+    Velocity,
+    Probability,
+}
+
+impl StepField {
+    pub fn get(self, step: &Step) -> u8 {
+        match self {
+            StepField::GateLength => step.gate_len,
+            StepField::Velocity => step.velocity,
+            StepField::Probability => step.probability,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -205,45 +848,86 @@ impl SequencerState {
             max_steps: MAX_STEPS as u8,
             patterns: [Pattern::new(); MAX_PATTERNS],
             song: Song::new(),
+            scenes: [None; MAX_SCENES],
+            fill_pattern: None,
+            settings: Settings::new(),
             play_mode: PlayMode::Pattern,
             song_position: 0,
             step_position: 0,
             visible_pattern: 0,
             playing_pattern: 0,
-            selected_tracks: 1,
+            edit: EditContext::new(),
+            follow: false,
             selected_step: None,
-            prev_selected_step: None,
+            held_step: None,
+            held_step_used: false,
+            record: RecordMode::Off,
+            record_micro: false,
+            recorded_steps: [0; MAX_TRACKS],
+            mutes: Mutes::new(),
+            queued_pattern: None,
+            param_cursor: Param::Bpm,
+            learn: false,
+            shift_held: false,
+            roll_steps: ROLL_LENGTHS[2],
+            history: History::new(),
+            clipboard: Clipboard::new(),
         }
     }
 
     #[inline]
     pub fn get_playing_pattern(&self) -> &Pattern {
-        let pattern_index = match self.play_mode {
+        &self.patterns[self.playing_pattern_index() as usize]
+    }
+
+    pub fn playing_pattern_index(&self) -> u8 {
+        match self.play_mode {
             PlayMode::Pattern => self.playing_pattern,
             PlayMode::Song => self.song.entries[self.song_position as usize],
-        };
-        &self.patterns[pattern_index as usize]
+        }
+    }
+
+    /// Mute and solo state in effect, from the settings' `mute_scope`.
+    pub fn mutes(&self) -> Mutes {
+        match self.settings.mute_scope {
+            MuteScope::Global => self.mutes,
+            MuteScope::Pattern => self.get_playing_pattern().mutes,
+        }
+    }
+
+    fn mutes_mut(&mut self) -> &mut Mutes {
+        match self.settings.mute_scope {
+            MuteScope::Global => &mut self.mutes,
+            MuteScope::Pattern => &mut self.patterns[self.playing_pattern_index() as usize].mutes,
+        }
     }
 
     pub fn is_track_selected(&self, track: u8) -> bool {
-        self.selected_tracks & (1 << track) != 0
+        self.edit.tracks & (1 << track) != 0
     }
 
     pub fn toggle_track(&mut self, track: u8) {
-        let selected_tracks = self.selected_tracks ^ (1 << track);
+        let selected_tracks = self.edit.tracks ^ (1 << track);
         if selected_tracks != 0 {
-            self.selected_tracks = selected_tracks;
+            self.edit.tracks = selected_tracks;
             mark_dirty(DIRTY_TRACK_SELECTION);
         }
     }
 
+    /// Whether the cell of the shown pattern is part of the edit selection.
+    pub fn is_cell_selected(&self, track: u8, step: u8) -> bool {
+        self.visible_pattern == self.edit.pattern
+            && self.is_track_selected(track)
+            && self.edit.steps & (1 << step) != 0
+    }
+
     pub fn select_only_track(&mut self, track: u8) {
-        self.selected_tracks = 1 << track;
+        self.edit.tracks = 1 << track;
         mark_dirty(DIRTY_TRACK_SELECTION);
     }
 
     pub fn selected_tracks_iter(&self) -> impl Iterator<Item = u8> {
-        iter_bits_u8(self.selected_tracks)
+        iter_bits_u8(self.edit.tracks)
     }
 
     pub fn get_all_tracks(&self) -> u8 {
@@ -253,55 +937,181 @@ impl SequencerState {
 
 #[interrupt]
 fn TIM3() {
-    unsafe {
-        // Clear interrupt flags (compare + update)
-        let tim3 = &*pac::TIM3::ptr();
-        tim3.sr().modify(|_, w| w.cc1if().clear_bit().uif().clear_bit());
-    }
+    let sr = unsafe { (*pac::TIM3::ptr()).sr().read() };
 
-    unsafe {
-        let tim3 = &*pac::TIM3::ptr();
-        let cnt = tim3.cnt().read().cnt().bits();
-        let overrun = cnt.wrapping_sub(LAST_CCR1) as u32;
-        #[cfg(feature = "perf")]
-        update_max_overrun(overrun);
+    // Step segments first, the CV tick can tolerate the extra latency.
+    if sr.cc1if().bit_is_set() {
+        unsafe {
+            // Clear interrupt flags (compare + update)
+            let tim3 = &*pac::TIM3::ptr();
+            tim3.sr().modify(|_, w| w.cc1if().clear_bit().uif().clear_bit());
+        }
 
-        if REMAINING_US == 0 {
-            advance_step_boundary();
+        unsafe {
+            let tim3 = &*pac::TIM3::ptr();
+            let cnt = tim3.cnt().read().cnt().bits();
+            let overrun = cnt.wrapping_sub(LAST_CCR1) as u32;
+            #[cfg(feature = "perf")]
+            update_max_overrun(overrun);
+
+            if REMAINING_US == 0 {
+                advance_step_boundary();
+            }
+            update_gate_outputs(step_elapsed_us());
+            let overrun_left = catch_up_overrun(overrun);
+            let base = cnt.wrapping_sub(overrun_left as u16);
+            schedule_next_step_segment_from(base);
+        }
+    }
+
+    if sr.cc2if().bit_is_set() {
+        unsafe {
+            let tim3 = &*pac::TIM3::ptr();
+            tim3.sr().modify(|_, w| w.cc2if().clear_bit());
+            let cnt = tim3.cnt().read().cnt().bits();
+            update_cv_outputs(step_elapsed_at(cnt));
+            let next = tim3.ccr2().read().ccr().bits().wrapping_add(CV_TICK_US);
+            tim3.ccr2().write(|w| w.ccr().bits(next));
         }
-        update_gate_outputs(step_elapsed_us());
-        let overrun_left = catch_up_overrun(overrun);
-        let base = cnt.wrapping_sub(overrun_left as u16);
-        schedule_next_step_segment_from(base);
     }
 }
 
 pub fn rebuild_rt_cache(sequencer_state: &SequencerState) {
+    rebuild_fill_cache(sequencer_state);
+    let pending = PENDING_CACHE.load(Ordering::Acquire);
+    let bank = free_cache_bank(pending);
+    fill_rt_cache(bank, sequencer_state, sequencer_state.get_playing_pattern());
+    // A pattern change is waiting for the wrap, let it wait with the fresh data. If it got
+    // applied in the meantime, the fresh data just replaces it.
+    if pending != NO_CACHE
+        && PENDING_CACHE
+            .compare_exchange(pending, bank, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    {
+        return;
+    }
+    ACTIVE_CACHE.store(bank, Ordering::Release);
+}
+
+/// Like `rebuild_rt_cache()`, but the step timer only switches to the new cache when the
+/// pattern wraps to its first step.
+pub fn rebuild_rt_cache_at_wrap(sequencer_state: &SequencerState) {
+    rebuild_fill_cache(sequencer_state);
+    let bank = free_cache_bank(PENDING_CACHE.load(Ordering::Acquire));
+    fill_rt_cache(bank, sequencer_state, sequencer_state.get_playing_pattern());
+    PENDING_CACHE.store(bank, Ordering::Release);
+}
+
+/// Rebuilds the cache the step timer plays during a fill. Each track is repeated up to
+/// `MAX_STEPS`, so the fill can be indexed by the playing pattern's step.
+pub fn rebuild_fill_cache(sequencer_state: &SequencerState) {
+    let Some(fill_pattern) = sequencer_state.fill_pattern else {
+        return;
+    };
+    let mut pattern = sequencer_state.patterns[fill_pattern as usize];
+    for track in &mut pattern.tracks {
+        let length = (track.length as usize).clamp(1, MAX_STEPS);
+        for index in length..MAX_STEPS {
+            track.steps[index] = track.steps[index % length];
+        }
+        track.length = MAX_STEPS as u8;
+    }
+    let bank = if FILL_CACHE.load(Ordering::Acquire) == FILL_BANKS[0] {
+        FILL_BANKS[1]
+    } else {
+        FILL_BANKS[0]
+    };
+    fill_rt_cache(bank, sequencer_state, &pattern);
+    FILL_CACHE.store(bank, Ordering::Release);
+}
+
+/// Plays the fill pattern while held, and for at least a bar from the press.
+pub fn fill_press() {
+    if PLAYING.load(Ordering::Relaxed) {
+        FILL_STEPS_LEFT.store(STEPS_PER_BAR as u8, Ordering::Relaxed);
+    }
+    FILL_HELD.store(true, Ordering::Relaxed);
+}
+
+pub fn fill_release() {
+    FILL_HELD.store(false, Ordering::Relaxed);
+}
+
+/// Loops the last `steps` steps until `roll_release()`. Starts with the next step.
+pub fn roll_press(steps: u8) {
+    ROLL_STEPS.store(steps, Ordering::Relaxed);
+}
+
+pub fn roll_release() {
+    ROLL_STEPS.store(0, Ordering::Relaxed);
+}
+
+pub fn roll_held() -> bool {
+    ROLL_STEPS.load(Ordering::Relaxed) != 0
+}
+
+/// Cache of what is heard, the fill pattern during a fill. Step positions always come from
+/// the `ACTIVE_CACHE`, so the pattern resumes where it would have been.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn playing_cache() -> &'static RtCache {
+    let cache_index = if FILL_PLAYING.load(Ordering::Acquire) {
+        FILL_CACHE.load(Ordering::Acquire)
+    } else {
+        ACTIVE_CACHE.load(Ordering::Acquire)
+    };
+    &RT_CACHE[cache_index as usize]
+}
+
+fn free_cache_bank(pending: u8) -> u8 {
     let active = ACTIVE_CACHE.load(Ordering::Acquire);
-    let inactive = active ^ 1;
-    let cache = unsafe { &mut RT_CACHE[inactive as usize] };
-    let pattern = sequencer_state.get_playing_pattern();
+    (0..3).find(|&bank| bank != active && bank != pending).unwrap_or(0)
+}
+
+fn fill_rt_cache(bank: u8, sequencer_state: &SequencerState, pattern: &Pattern) {
+    let cache = unsafe { &mut RT_CACHE[bank as usize] };
+    let transpose = sequencer_state.settings.transpose;
     for track_index in 0..MAX_TRACKS {
         let track = &pattern.tracks[track_index];
         cache.lengths[track_index] = track.length;
+        cache.windows[track_index] = track.window();
+        cache.offsets[track_index] = track.offset;
+        cache.reset_modes[track_index] = track.reset_mode;
+        cache.output_modes[track_index] = track.output_mode;
+        cache.trigger_ms[track_index] = track.trigger_ms;
+        cache.cv_modes[track_index] = track.cv_mode;
+        cache.lfos[track_index] = track.lfo;
+        cache.envelopes[track_index] = track.envelope;
 
         let mut mask: u16 = 0;
+        let mut tie_mask: u16 = 0;
         for step_index in 0..MAX_STEPS {
             let step = track.steps[step_index];
-            cache.pitches[track_index][step_index] = step.pitch;
+            let pitch = step.pitch.saturating_add_signed(transpose).min(127);
+            cache.pitches[track_index][step_index] = pitch;
             cache.gate_lengths[track_index][step_index] = step.gate_len;
+            cache.velocities[track_index][step_index] = step.velocity;
+            cache.micros[track_index][step_index] = step.micro.min(MAX_MICRO);
+            cache.probabilities[track_index][step_index] = step.probability;
             if step.active {
                 mask |= 1u16 << step_index;
+                if step.tie {
+                    tie_mask |= 1u16 << step_index;
+                }
             }
         }
         cache.gate_masks[track_index] = mask;
+        cache.tie_masks[track_index] = tie_mask & mask;
     }
-    ACTIVE_CACHE.store(inactive, Ordering::Release);
+    cache.clock_out = sequencer_state.settings.clock_out;
+    cache.reset_out = sequencer_state.settings.reset_out;
+    cache.midi_channels = sequencer_state.settings.midi_channels;
+    cache.swing = sequencer_state.settings.swing;
 }
 
 fn pulses_per_step_from_ppqn(ppqn: u32) -> Option<u32> {
     match ppqn {
         4 => Some(1),
+        8 => Some(2),
         24 => Some(6),
         _ => None,
     }
@@ -323,33 +1133,56 @@ pub fn init_step_timer(tim3: TIM3, clocks: &Clocks) {
     tim3.cnt().write(|w| unsafe { w.cnt().bits(0) });
     tim3.egr().write(|w| w.ug().set_bit());
     tim3.sr().modify(|_, w| w.cc1if().clear_bit().uif().clear_bit());
+    tim3.sr().modify(|_, w| w.cc2if().clear_bit());
 }
 
-pub fn set_bpm(bpm: u32) {
-    BPM.store(bpm, Ordering::Relaxed);
-    mark_dirty(DIRTY_BPM);
+/// Step interval as a fraction of microseconds.
+fn step_interval_from_bpm(bpm: u32) -> (u64, u32) {
     let ppqn = PPQN.load(Ordering::Relaxed);
     let pulses_per_step = pulses_per_step_from_ppqn(ppqn).unwrap_or(1);
     let denom = bpm.saturating_mul(ppqn).max(1);
     let numer = 60_000_000u64 * pulses_per_step as u64;
-    let base_us = (numer / denom as u64) as u32;
-    let rem = (numer % denom as u64) as u32;
+    (numer, denom)
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn store_step_interval(numer: u64, denom: u32) {
+    let denom = denom.max(1);
+    STEP_INTERVAL.base_us = ((numer / denom as u64) as u32).max(1);
+    STEP_INTERVAL.rem = (numer % denom as u64) as u32;
+    STEP_INTERVAL.denom = denom;
+    STEP_INTERVAL.acc = 0;
+}
+
+pub fn set_bpm(bpm: u32) {
+    INTERNAL_BPM.store(bpm, Ordering::Relaxed);
+    if EXT_CLOCK_LOCKED.load(Ordering::Relaxed) {
+        // Takes effect when the external clock stops.
+        return;
+    }
+    BPM.store(bpm, Ordering::Relaxed);
+    mark_dirty(DIRTY_BPM);
+    let (numer, denom) = step_interval_from_bpm(bpm);
 
     cortex_m::interrupt::free(|_| unsafe {
-        STEP_INTERVAL.base_us = base_us.max(1);
-        STEP_INTERVAL.rem = rem;
-        STEP_INTERVAL.denom = denom;
-        STEP_INTERVAL.acc = 0;
+        store_step_interval(numer, denom);
         if PLAYING.load(Ordering::Relaxed) {
             let tim3 = &*pac::TIM3::ptr();
             LAST_CCR1 = tim3.cnt().read().cnt().bits();
-            let step_us = get_next_step_interval_us();
-            STEP_US = step_us;
-            REMAINING_US = step_us;
             let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
             let cache = &RT_CACHE[cache_index as usize];
-            let step = CURRENT_STEP.load(Ordering::Relaxed);
-            configure_gates_for_step(step, cache, step_us);
+            let grid_us = get_next_step_interval_us();
+            let step_us = swing_step_us(grid_us, POSITION_STEP, cache);
+            STEP_US = step_us;
+            STEP_NOMINAL_US = step_us;
+            CLOCK_STEP_US = grid_us;
+            REMAINING_US = step_us;
+            let sound = playing_cache();
+            configure_gates_for_step(sound, step_us);
+            let rates = &raw mut LFO_PHASE_PER_US;
+            for (per_us, &lfo) in (*rates).iter_mut().zip(&sound.lfos) {
+                *per_us = lfo_phase_per_us(lfo, step_us);
+            }
             schedule_next_step_segment_from(LAST_CCR1);
             tim3.dier().modify(|_, w| w.cc1ie().set_bit().uie().clear_bit());
             tim3.cr1().modify(|_, w| w.cen().set_bit());
@@ -360,18 +1193,37 @@ pub fn set_bpm(bpm: u32) {
 pub fn start_playback() {
     cortex_m::interrupt::free(|_| unsafe {
         PLAYING.store(true, Ordering::Relaxed);
+        // From the top every track starts over, whatever its reset mode. Otherwise everything
+        // continues from where it was paused.
+        if move_tracks(Transport::Play { from: SONG_POSITION }) {
+            NEXT_STEP.store(0, Ordering::Relaxed);
+            LFO_STEPS = [0; MAX_TRACKS];
+            RESET_OUT_DUE = true;
+            midi_io::send(MidiMessage::Start);
+        } else {
+            midi_io::send(MidiMessage::SongPosition(SONG_POSITION));
+            midi_io::send(MidiMessage::Continue);
+        }
         STEP_INTERVAL.acc = 0;
+        // With an external clock, the first step waits for the next pulse.
+        EXT_STEPS_ALLOWED = 0;
+        clock::restart();
         let tim3 = &*pac::TIM3::ptr();
         tim3.cr1().modify(|_, w| w.cen().clear_bit());
         tim3.cnt().write(|w| w.cnt().bits(0));
         tim3.sr().modify(|_, w| w.cc1if().clear_bit().uif().clear_bit());
+        tim3.sr().modify(|_, w| w.cc2if().clear_bit());
         LAST_CCR1 = 0;
         clear_gate_state();
+        clear_clock_outputs();
         let step_us = get_next_step_interval_us();
         STEP_US = step_us;
+        STEP_NOMINAL_US = step_us;
+        CLOCK_STEP_US = step_us;
         REMAINING_US = step_us;
         schedule_next_step_segment_from(LAST_CCR1);
-        tim3.dier().modify(|_, w| w.cc1ie().set_bit().uie().clear_bit());
+        tim3.ccr2().write(|w| w.ccr().bits(CV_TICK_US));
+        tim3.dier().modify(|_, w| w.cc1ie().set_bit().cc2ie().set_bit().uie().clear_bit());
         tim3.cr1().modify(|_, w| w.cen().set_bit());
     });
 }
@@ -381,38 +1233,168 @@ pub fn pause_playback() {
         PLAYING.store(false, Ordering::Relaxed);
         // TODO: For now we only force a single gate low; expand to all 8 channels.
         clear_gate_state();
+        clear_envelopes();
+        clear_clock_outputs();
         let tim3 = &*pac::TIM3::ptr();
-        tim3.dier().modify(|_, w| w.cc1ie().clear_bit().uie().clear_bit());
+        tim3.dier().modify(|_, w| w.cc1ie().clear_bit().cc2ie().clear_bit().uie().clear_bit());
         tim3.cr1().modify(|_, w| w.cen().clear_bit());
         tim3.sr().modify(|_, w| w.cc1if().clear_bit().uif().clear_bit());
+        tim3.sr().modify(|_, w| w.cc2if().clear_bit());
         REMAINING_US = 0;
+        RESET_OUT_DUE = false;
+        midi_io::send(MidiMessage::Stop);
     });
 }
 
-pub fn toggle_playback() -> bool {
-    if PLAYING.load(Ordering::Relaxed) {
-        pause_playback();
-        false
-    } else {
-        start_playback();
-        true
-    }
+/// Sets where playback continues from, `position` wrapped by the pattern length.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn move_song_position(position: u16) {
+    let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
+    let length = RT_CACHE[cache_index as usize].lengths[0].clamp(1, MAX_STEPS as u8);
+    jump_to(position, (position % length as u16) as u8);
 }
 
-#[cfg(feature = "perf")]
-pub fn take_overrun_stats() -> (u32, u32) {
-    let missed_step_segments = OVERRUN_MISSED_STEP_SEGMENTS.swap(0, Ordering::Relaxed);
-    let max_overrun_us = OVERRUN_MAX_US.swap(0, Ordering::Relaxed);
-    (missed_step_segments, max_overrun_us)
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn jump_to(position: u16, step: u8) {
+    NEXT_STEP.store(step, Ordering::Relaxed);
+    SONG_POSITION = position & 0x3FFF;
+    move_tracks(Transport::Jump(step));
+}
+
+/// Like `set_song_position()`, for a Song Position Pointer that the clock leader sent. Also
+/// moves the tracks in `ResetMode::External`.
+pub fn external_song_position(sequencer_state: &mut SequencerState, position: u16) {
+    set_song_position(sequencer_state, position);
+    cortex_m::interrupt::free(|_| unsafe {
+        move_tracks(Transport::SongPosition(NEXT_STEP.load(Ordering::Relaxed)));
+    });
 }
 
+/// Called for a rising edge at the reset input. The tracks in `ResetMode::External` play their
+/// start step next.
 #[allow(unsafe_op_in_unsafe_fn)]
-unsafe fn get_next_step_interval_us() -> u32 {
-    // Bresenham-style error accumulator for fractional microsecond intervals.
-    let mut acc = STEP_INTERVAL.acc;
-    let rem = STEP_INTERVAL.rem;
-    let denom = STEP_INTERVAL.denom;
-    let mut extra = 0;
+pub(crate) unsafe fn external_reset() {
+    move_tracks(Transport::ResetInput);
+}
+
+/// Resets the tracks as `event` calls for in their modes. Returns false if it leaves them be.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn move_tracks(event: Transport) -> bool {
+    let Some(reset) = event.reset() else {
+        return false;
+    };
+    let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
+    let positions = &raw mut POSITIONS;
+    (*positions).reset(&RT_CACHE[cache_index as usize].reset_modes, reset);
+    true
+}
+
+/// Pause and rewind to the first step.
+pub fn stop_playback(sequencer_state: &mut SequencerState) {
+    pause_playback();
+    FILL_STEPS_LEFT.store(0, Ordering::Relaxed);
+    set_song_position(sequencer_state, 0);
+}
+
+/// Jump to `position` steps from the start of the song. In song mode this picks the song entry
+/// the position falls in and switches to its pattern, otherwise the pattern plays from
+/// `position % length`. Followers are told with Song Position Pointer, while playing it's
+/// wrapped in Stop/Continue since most devices only accept it when stopped.
+pub fn set_song_position(sequencer_state: &mut SequencerState, position: u16) {
+    let position = position & 0x3FFF;
+    let mut entry_step = None;
+    if matches!(sequencer_state.play_mode, PlayMode::Song) {
+        let patterns = &sequencer_state.patterns;
+        let length_of = |pattern: u8| patterns[pattern as usize % MAX_PATTERNS].tracks[0].length;
+        if let Some((entry, step)) = sequencer_state.song.locate(position, length_of) {
+            sequencer_state.song_position = entry;
+            sequencer_state.queued_pattern = None;
+            publish_mutes(sequencer_state, ChangeAt::Now);
+            // The step timer has to be on the entry's pattern before it jumps into it.
+            rebuild_rt_cache(sequencer_state);
+            mark_dirty(DIRTY_PATTERN);
+            entry_step = Some(step);
+        }
+    }
+    cortex_m::interrupt::free(|_| unsafe {
+        match entry_step {
+            Some(step) => jump_to(position, step),
+            None => move_song_position(position),
+        }
+        let playing = PLAYING.load(Ordering::Relaxed);
+        if playing {
+            midi_io::send(MidiMessage::Stop);
+        }
+        midi_io::send(MidiMessage::SongPosition(position));
+        if playing {
+            midi_io::send(MidiMessage::Continue);
+        }
+    });
+}
+
+/// Where a note or hit recorded now lands, as each track's step and microtiming. Without
+/// `micro`, past the middle of a step a hit is early for the next step rather than late for the
+/// current one. With it, the hit stays on the current step and keeps its offset.
+pub fn record_position(micro: bool) -> ([u8; MAX_TRACKS], u8) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let tim3 = &*pac::TIM3::ptr();
+        let elapsed = step_elapsed_at(tim3.cnt().read().cnt().bits());
+        let step_us = STEP_NOMINAL_US.max(1);
+        if micro {
+            let offset = (elapsed as u64 * 100 / step_us as u64).min(MAX_MICRO as u64);
+            (TRACK_STEPS, offset as u8)
+        } else if elapsed >= step_us / 2 {
+            (next_track_steps(), 0)
+        } else {
+            (TRACK_STEPS, 0)
+        }
+    })
+}
+
+/// Step each track is playing, within its loop window.
+pub fn track_steps() -> [u8; MAX_TRACKS] {
+    cortex_m::interrupt::free(|_| unsafe { TRACK_STEPS })
+}
+
+/// Step each track plays next, within its loop window.
+pub fn upcoming_track_steps() -> [u8; MAX_TRACKS] {
+    cortex_m::interrupt::free(|_| unsafe { next_track_steps() })
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn next_track_steps() -> [u8; MAX_TRACKS] {
+    let sound = playing_cache();
+    let positions = &raw const POSITIONS;
+    core::array::from_fn(|track_index| {
+        let position = (*positions).get(track_index);
+        sound.windows[track_index].step_at(sound.offsets[track_index], position, 0)
+    })
+}
+
+pub fn toggle_playback() -> bool {
+    if PLAYING.load(Ordering::Relaxed) {
+        pause_playback();
+        false
+    } else {
+        start_playback();
+        true
+    }
+}
+
+#[cfg(feature = "perf")]
+pub fn take_overrun_stats() -> (u32, u32) {
+    let missed_step_segments = OVERRUN_MISSED_STEP_SEGMENTS.swap(0, Ordering::Relaxed);
+    let max_overrun_us = OVERRUN_MAX_US.swap(0, Ordering::Relaxed);
+    (missed_step_segments, max_overrun_us)
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn get_next_step_interval_us() -> u32 {
+    // Bresenham-style error accumulator for fractional microsecond intervals.
+    let mut acc = STEP_INTERVAL.acc;
+    let rem = STEP_INTERVAL.rem;
+    let denom = STEP_INTERVAL.denom;
+    let mut extra = 0;
     if rem != 0 && denom != 0 {
         acc = acc.wrapping_add(rem);
         if acc >= denom {
@@ -450,6 +1432,25 @@ fn gate_len_to_us(step_us: u32, gate_len: u8) -> u32 {
     us as u32
 }
 
+#[inline]
+fn trigger_len_to_us(step_us: u32, trigger_ms: u8) -> u32 {
+    if step_us == 0 {
+        return 0;
+    }
+    let trigger_ms = trigger_ms.clamp(MIN_TRIGGER_MS, MAX_TRIGGER_MS) as u32;
+    // Keep at least half a step low so consecutive triggers don't merge into one long gate at
+    // high tempos.
+    (trigger_ms * 1000).min(step_us / 2).max(1)
+}
+
+#[inline]
+fn output_len_us(mode: OutputMode, step_us: u32, gate_len: u8, trigger_ms: u8) -> u32 {
+    match mode {
+        OutputMode::Gate => gate_len_to_us(step_us, gate_len),
+        OutputMode::Trigger | OutputMode::Clock => trigger_len_to_us(step_us, trigger_ms),
+    }
+}
+
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn gate_set_high(track_index: usize) {
     if track_index == GATE_TRACK_INDEX {
@@ -470,26 +1471,237 @@ unsafe fn gate_set_low(track_index: usize) {
     }
 }
 
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn set_gate(track_index: usize, high: bool) {
+    let bit = 1u8 << track_index;
+    let was_high = GATE_STATE & bit != 0;
+    if high {
+        GATE_STATE |= bit;
+        gate_set_high(track_index);
+        if !was_high {
+            note_on(track_index, TRACK_STEPS[track_index]);
+        }
+    } else {
+        GATE_STATE &= !bit;
+        gate_set_low(track_index);
+        if was_high {
+            envelope_gate_off(track_index);
+            midi_note_off(track_index);
+        }
+    }
+}
+
+/// Starts the envelope and MIDI note of a step whose gate just went high.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn note_on(track_index: usize, step: u8) {
+    let cache = playing_cache();
+    let active = cache.gate_masks[track_index] & (1 << step) != 0;
+    if active && cache.cv_modes[track_index] == CvMode::Envelope {
+        envelope_trigger(track_index, cache.velocities[track_index][step as usize]);
+    }
+    midi_note_on(track_index, step);
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn midi_note_on(track_index: usize, step: u8) {
+    let cache = playing_cache();
+    // Clock mode pulses on every step, those aren't notes.
+    if cache.output_modes[track_index] == OutputMode::Clock {
+        return;
+    }
+    midi_note_off(track_index);
+    let channel = cache.midi_channels[track_index];
+    let note = cache.pitches[track_index][step as usize];
+    midi_io::send(MidiMessage::NoteOn {
+        channel,
+        note,
+        velocity: cache.velocities[track_index][step as usize].max(1),
+    });
+    MIDI_NOTE[track_index] = Some((channel, note));
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn midi_note_off(track_index: usize) {
+    if let Some((channel, note)) = MIDI_NOTE[track_index].take() {
+        midi_io::send(MidiMessage::NoteOff { channel, note, velocity: 0 });
+    }
+}
+
+/// Gates that stay high into an active step (100% gate length) have no edge, so their note is
+/// restarted here. Delayed steps drop the gate first and get a proper edge.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn retrigger_held_notes(cache: &RtCache) {
+    for (track_index, step) in TRACK_STEPS.into_iter().enumerate() {
+        let step_bit = 1u16 << step;
+        let held = GATE_STATE & (1 << track_index) != 0;
+        let delayed = cache.micros[track_index][step as usize] != 0;
+        let tied = cache.tie_masks[track_index] & step_bit != 0;
+        if held && !delayed && !tied && (cache.gate_masks[track_index] & step_bit) != 0 {
+            note_on(track_index, step);
+        }
+    }
+}
+
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn clear_gate_state() {
     for track_index in 0..MAX_TRACKS {
+        STEP_GATE_START_US[track_index] = 0;
         STEP_GATE_LEN_US[track_index] = 0;
         STEP_GATE_ACTIVE[track_index] = false;
-        gate_set_low(track_index);
+        set_gate(track_index, false);
+    }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn clock_out_set(active: bool, inverted: bool) {
+    let gpioc = &*pac::GPIOC::ptr();
+    if active != inverted {
+        gpioc.bsrr().write(|w| w.bs10().set_bit());
+    } else {
+        gpioc.bsrr().write(|w| w.br10().set_bit());
+    }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn reset_out_set(active: bool, inverted: bool) {
+    let gpioc = &*pac::GPIOC::ptr();
+    if active != inverted {
+        gpioc.bsrr().write(|w| w.bs11().set_bit());
+    } else {
+        gpioc.bsrr().write(|w| w.br11().set_bit());
     }
 }
 
 #[allow(unsafe_op_in_unsafe_fn)]
-unsafe fn configure_gates_for_step(step: u8, cache: &RtCache, step_us: u32) {
-    let step_bit = 1u16 << step;
+unsafe fn clear_clock_outputs() {
+    let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
+    let cache = &RT_CACHE[cache_index as usize];
+    CLOCK_TICK_IN_STEP = CLOCK_TICKS_PER_STEP;
+    CLOCK_TICKS_END = CLOCK_TICKS_PER_STEP;
+    CLOCK_OUT_END_US = 0;
+    RESET_OUT_END_US = 0;
+    clock_out_set(false, cache.clock_out.inverted);
+    reset_out_set(false, cache.reset_out.inverted);
+}
+
+/// When `tick` is due, from the start of the current step. Ticks the first step of a swung pair
+/// already sent come out as 0.
+#[inline]
+fn clock_tick_us(tick: u8) -> u32 {
+    let (grid_us, pair_us) = unsafe { (CLOCK_STEP_US, CLOCK_PAIR_US) };
+    let tick_us = (grid_us as u64 * tick as u64 / CLOCK_TICKS_PER_STEP as u64) as u32;
+    tick_us.saturating_sub(pair_us)
+}
+
+/// Pulse width capped to half the pulse period so pulses never merge, and so they always end
+/// within the step they started in.
+#[inline]
+fn clock_pulse_us(pulse_ms: u8, ticks_per_pulse: u32) -> u32 {
+    let pulse_us = pulse_ms.clamp(MIN_CLOCK_PULSE_MS, MAX_CLOCK_PULSE_MS) as u32 * 1000;
+    let tick_us = unsafe { CLOCK_STEP_US } / CLOCK_TICKS_PER_STEP as u32;
+    let period_us = tick_us * ticks_per_pulse.min(CLOCK_TICKS_PER_STEP as u32);
+    pulse_us.min(period_us / 2).max(1)
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn update_clock_outputs(elapsed_us: u32) {
+    let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
+    let cache = &RT_CACHE[cache_index as usize];
+    let clock_out = cache.clock_out;
+    while CLOCK_TICK_IN_STEP < CLOCK_TICKS_END {
+        let tick = CLOCK_TICK_IN_STEP;
+        let tick_us = clock_tick_us(tick);
+        if tick_us > elapsed_us {
+            break;
+        }
+        CLOCK_TICK_IN_STEP += 1;
+        midi_io::send(MidiMessage::Clock);
+        let ticks_per_pulse = clock_out.rate.ticks_per_pulse();
+        let position = CLOCK_POSITION + tick as u32;
+        if position.is_multiple_of(ticks_per_pulse) {
+            CLOCK_OUT_END_US = tick_us + clock_pulse_us(clock_out.pulse_ms, ticks_per_pulse);
+        }
+        if tick == 0 && RESET_OUT_DUE {
+            RESET_OUT_DUE = false;
+            let ticks_per_step = CLOCK_TICKS_PER_STEP as u32;
+            RESET_OUT_END_US = clock_pulse_us(cache.reset_out.pulse_ms, ticks_per_step);
+        }
+    }
+    clock_out_set(elapsed_us < CLOCK_OUT_END_US, clock_out.inverted);
+    reset_out_set(elapsed_us < RESET_OUT_END_US, cache.reset_out.inverted);
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn time_until_clock_change(elapsed_us: u32) -> u32 {
+    let mut min = u32::MAX;
+    if CLOCK_TICK_IN_STEP < CLOCK_TICKS_END {
+        let tick_us = clock_tick_us(CLOCK_TICK_IN_STEP);
+        if tick_us > elapsed_us {
+            min = tick_us - elapsed_us;
+        }
+    }
+    for end_us in [CLOCK_OUT_END_US, RESET_OUT_END_US] {
+        if end_us > elapsed_us {
+            min = min.min(end_us - elapsed_us);
+        }
+    }
+    min
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn clear_envelopes() {
+    let cache = playing_cache();
+    for track_index in 0..MAX_TRACKS {
+        ENV_STAGE[track_index] = EnvStage::Idle;
+        ENV_LEVEL[track_index] = 0;
+        if cache.cv_modes[track_index] == CvMode::Envelope {
+            cv::write(track_index, 0);
+        }
+    }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn configure_gates_for_step(cache: &RtCache, step_us: u32) {
+    let silenced = SILENCED_TRACKS.load(Ordering::Relaxed);
     for track_index in 0..MAX_TRACKS {
-        let active = (cache.gate_masks[track_index] & step_bit) != 0;
+        let step = TRACK_STEPS[track_index];
+        let step_bit = 1u16 << step;
+        let next_bit = 1u16 << cache.windows[track_index].next(step);
+        let mode = cache.output_modes[track_index];
+        let tied = cache.tie_masks[track_index] & step_bit != 0;
+        // Muting only skips gates here, the pattern data stays as it is.
+        let muted = silenced & (1 << track_index) != 0;
+        let active = match mode {
+            OutputMode::Clock => !muted,
+            // Tied steps follow the note they hold.
+            OutputMode::Gate | OutputMode::Trigger => {
+                !muted
+                    && (cache.gate_masks[track_index] & step_bit) != 0
+                    && (tied || roll(cache.probabilities[track_index][step as usize]))
+            }
+        };
         STEP_GATE_ACTIVE[track_index] = active;
         if active {
-            let gate_len = clamp_gate_len(cache.gate_lengths[track_index][step as usize]);
-            let gate_len_us = gate_len_to_us(step_us, gate_len);
-            STEP_GATE_LEN_US[track_index] = gate_len_us;
+            let mut gate_len = clamp_gate_len(cache.gate_lengths[track_index][step as usize]);
+            // A tie on the next step holds the gate through into it.
+            if mode == OutputMode::Gate && cache.tie_masks[track_index] & next_bit != 0 {
+                gate_len = MAX_GATE_LENGTH;
+            }
+            let gate_len_us =
+                output_len_us(mode, step_us, gate_len, cache.trigger_ms[track_index]);
+            // Delayed gates still end with the step.
+            let start_us = match mode {
+                OutputMode::Clock => 0,
+                OutputMode::Gate | OutputMode::Trigger if tied => 0,
+                OutputMode::Gate | OutputMode::Trigger => {
+                    let micro = cache.micros[track_index][step as usize].min(MAX_MICRO);
+                    (step_us as u64 * micro as u64 / 100) as u32
+                }
+            };
+            STEP_GATE_START_US[track_index] = start_us;
+            STEP_GATE_LEN_US[track_index] = gate_len_us.min(step_us - start_us);
         } else {
+            STEP_GATE_START_US[track_index] = 0;
             STEP_GATE_LEN_US[track_index] = 0;
         }
     }
@@ -503,29 +1715,186 @@ fn step_elapsed_us() -> u32 {
     step_us.saturating_sub(remaining.min(step_us))
 }
 
+/// Elapsed time in the current step at timer count `cnt`. Unlike `step_elapsed_us()`, which is
+/// only exact at segment boundaries, this can be used in between them.
+#[inline]
+fn step_elapsed_at(cnt: u16) -> u32 {
+    let since_segment_start = cnt.wrapping_sub(unsafe { SEGMENT_START }) as u32;
+    unsafe { SEGMENT_ELAPSED_US + since_segment_start.min(SEGMENT_US) }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn next_random() -> u32 {
+    // xorshift32
+    let mut x = RNG_STATE;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    RNG_STATE = x;
+    x
+}
+
+/// True with a chance of `probability` percent.
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn roll(probability: u8) -> bool {
+    probability >= MAX_PROBABILITY || next_random() % (MAX_PROBABILITY as u32) < probability as u32
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn configure_cv_for_step(cache: &RtCache) {
+    for track_index in 0..MAX_TRACKS {
+        let step = TRACK_STEPS[track_index];
+        let step_bit = 1u16 << step;
+        match cache.cv_modes[track_index] {
+            CvMode::Pitch => {
+                let tied = cache.tie_masks[track_index] & step_bit != 0;
+                if (cache.gate_masks[track_index] & step_bit) != 0 && !tied {
+                    let pitch = cache.pitches[track_index][step as usize];
+                    cv::write(track_index, cv::pitch_to_cv(pitch));
+                }
+            }
+            CvMode::Lfo => {
+                let lfo = cache.lfos[track_index];
+                let rate = lfo.rate.max(1) as u32;
+                let position = LFO_STEPS[track_index] % rate;
+                let span = (1u64 << 32) / rate as u64;
+                let base = (span * position as u64) as u32;
+                LFO_PHASE_BASE[track_index] = base;
+                LFO_PHASE_PER_US[track_index] = lfo_phase_per_us(lfo, STEP_NOMINAL_US);
+                if position == 0 && lfo.shape == LfoShape::SampleHold {
+                    LFO_HELD[track_index] = (next_random() >> 16) as u16;
+                }
+                cv::write(track_index, cv::lfo_value(lfo.shape, base, LFO_HELD[track_index]));
+            }
+            // Triggered by the gate, see `note_on()`.
+            CvMode::Envelope => {}
+        }
+        LFO_STEPS[track_index] = LFO_STEPS[track_index].wrapping_add(1);
+    }
+}
+
+/// How far the phase of `lfo` moves per µs of a `step_us` step, a step being `1 / rate` of
+/// the cycle. Truncating it loses less than the step length in phase, out of 2^32.
+fn lfo_phase_per_us(lfo: Lfo, step_us: u32) -> u32 {
+    let span = (1u64 << 32) / lfo.rate.max(1) as u64;
+    (span / step_us.max(1) as u64) as u32
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn envelope_trigger(track_index: usize, velocity: u8) {
+    let velocity = velocity.min(MAX_VELOCITY) as u32;
+    ENV_PEAK[track_index] = ENV_MAX_LEVEL / MAX_VELOCITY as u32 * velocity;
+    // Attack starts from the current level so retriggers don't click.
+    ENV_STAGE[track_index] = EnvStage::Attack;
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn envelope_gate_off(track_index: usize) {
+    let cache = playing_cache();
+    if cache.envelopes[track_index].mode != EnvelopeMode::Adsr {
+        return;
+    }
+    match ENV_STAGE[track_index] {
+        EnvStage::Attack | EnvStage::Decay | EnvStage::Sustain => {
+            ENV_STAGE[track_index] = EnvStage::Release;
+        }
+        EnvStage::Idle | EnvStage::Release => {}
+    }
+}
+
+/// Level change per CV tick for a segment that sweeps the full range in `ms`.
+#[inline]
+fn envelope_rate(ms: u16) -> u32 {
+    let ticks = (ms as u32 * 1000 / CV_TICK_US as u32).max(1);
+    ENV_MAX_LEVEL / ticks
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn update_envelope(track_index: usize, envelope: &Envelope) -> u16 {
+    let (stage, level) = envelope_tick(
+        envelope,
+        ENV_STAGE[track_index],
+        ENV_LEVEL[track_index],
+        ENV_PEAK[track_index],
+    );
+    ENV_STAGE[track_index] = stage;
+    ENV_LEVEL[track_index] = level;
+    (level >> 8).min(cv::CV_MAX as u32) as u16
+}
+
+/// One CV tick of an envelope in `stage` at `level`, returns where it goes next.
+fn envelope_tick(envelope: &Envelope, stage: EnvStage, level: u32, peak: u32) -> (EnvStage, u32) {
+    match stage {
+        EnvStage::Idle => (EnvStage::Idle, 0),
+        EnvStage::Attack => {
+            let level = level.saturating_add(envelope_rate(envelope.attack_ms));
+            if level >= peak { (EnvStage::Decay, peak) } else { (EnvStage::Attack, level) }
+        }
+        EnvStage::Decay => {
+            let target = match envelope.mode {
+                EnvelopeMode::Adsr => peak / 100 * envelope.sustain.min(100) as u32,
+                EnvelopeMode::Ad => 0,
+            };
+            let level = level.saturating_sub(envelope_rate(envelope.decay_ms));
+            if level > target {
+                (EnvStage::Decay, level)
+            } else if target == 0 {
+                (EnvStage::Idle, 0)
+            } else {
+                (EnvStage::Sustain, target)
+            }
+        }
+        EnvStage::Sustain => (EnvStage::Sustain, level),
+        EnvStage::Release => {
+            let level = level.saturating_sub(envelope_rate(envelope.release_ms));
+            if level == 0 { (EnvStage::Idle, 0) } else { (EnvStage::Release, level) }
+        }
+    }
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn update_cv_outputs(elapsed_us: u32) {
+    let cache = playing_cache();
+    // Nominal length, so the phase stops at the step end while waiting for an external clock.
+    let elapsed_us = elapsed_us.min(STEP_NOMINAL_US);
+    for track_index in 0..MAX_TRACKS {
+        match cache.cv_modes[track_index] {
+            CvMode::Pitch => {}
+            CvMode::Lfo => {
+                let offset = LFO_PHASE_PER_US[track_index].wrapping_mul(elapsed_us);
+                let phase = LFO_PHASE_BASE[track_index].wrapping_add(offset);
+                let shape = cache.lfos[track_index].shape;
+                cv::write(track_index, cv::lfo_value(shape, phase, LFO_HELD[track_index]));
+            }
+            CvMode::Envelope => {
+                let value = update_envelope(track_index, &cache.envelopes[track_index]);
+                cv::write(track_index, value);
+            }
+        }
+    }
+}
+
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn update_gate_outputs(elapsed_us: u32) {
     for track_index in 0..MAX_TRACKS {
         if !STEP_GATE_ACTIVE[track_index] {
-            gate_set_low(track_index);
+            set_gate(track_index, false);
             continue;
         }
         let gate_len_us = STEP_GATE_LEN_US[track_index];
         if gate_len_us == 0 {
-            gate_set_low(track_index);
+            set_gate(track_index, false);
             continue;
         }
-        if elapsed_us < gate_len_us {
-            gate_set_high(track_index);
-        } else {
-            gate_set_low(track_index);
-        }
+        let start_us = STEP_GATE_START_US[track_index];
+        set_gate(track_index, elapsed_us >= start_us && elapsed_us < start_us + gate_len_us);
     }
+    update_clock_outputs(elapsed_us);
 }
 
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn time_until_gate_change(elapsed_us: u32, step_us: u32) -> u32 {
-    let mut min = u32::MAX;
+    let mut min = time_until_clock_change(elapsed_us);
     for track_index in 0..MAX_TRACKS {
         if !STEP_GATE_ACTIVE[track_index] {
             continue;
@@ -534,8 +1903,11 @@ unsafe fn time_until_gate_change(elapsed_us: u32, step_us: u32) -> u32 {
         if gate_len_us == 0 {
             continue;
         }
-        let next = if elapsed_us < gate_len_us {
-            gate_len_us - elapsed_us
+        let start_us = STEP_GATE_START_US[track_index];
+        let next = if elapsed_us < start_us {
+            start_us - elapsed_us
+        } else if elapsed_us < start_us + gate_len_us {
+            start_us + gate_len_us - elapsed_us
         } else {
             continue;
         };
@@ -562,29 +1934,184 @@ fn update_max_overrun(overrun_us: u32) {
     }
 }
 
+/// Called from the clock input ISR with the measured step interval (`numer / denom` us).
+#[allow(unsafe_op_in_unsafe_fn)]
+pub(crate) unsafe fn external_clock_tempo(numer: u64, denom: u32, bpm: u32) {
+    store_step_interval(numer, denom);
+    EXT_CLOCK_LOCKED.store(true, Ordering::Relaxed);
+    if BPM.swap(bpm, Ordering::Relaxed) != bpm {
+        mark_dirty(DIRTY_BPM);
+    }
+}
+
+/// Called from the clock input ISR on pulses that fall on a step boundary. Starts the next step
+/// right away, and lets the step timer fill in `steps_per_pulse - 1` steps until the next pulse.
+#[allow(unsafe_op_in_unsafe_fn)]
+pub(crate) unsafe fn external_clock_step(steps_per_pulse: u8) {
+    if !PLAYING.load(Ordering::Relaxed) {
+        return;
+    }
+    // Steps the timer didn't get to before this pulse are skipped to stay in phase.
+    let skipped = EXT_STEPS_ALLOWED;
+    if skipped != 0 {
+        let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
+        let length = RT_CACHE[cache_index as usize].lengths[0].clamp(1, MAX_STEPS as u8);
+        let next = (NEXT_STEP.load(Ordering::Relaxed) + skipped) % length;
+        NEXT_STEP.store(next, Ordering::Relaxed);
+        let positions = &raw mut POSITIONS;
+        (*positions).advance(&RT_CACHE[cache_index as usize].reset_modes, skipped, next);
+        SONG_POSITION = SONG_POSITION.wrapping_add(skipped as u16) & 0x3FFF;
+    }
+    EXT_STEPS_ALLOWED = steps_per_pulse.saturating_sub(1);
+    let tim3 = &*pac::TIM3::ptr();
+    tim3.sr().modify(|_, w| w.cc1if().clear_bit());
+    let cnt = tim3.cnt().read().cnt().bits();
+    start_next_step();
+    schedule_next_step_segment_from(cnt);
+}
+
+/// Called when the external clock stops or is deselected. Goes back to the internal tempo.
+#[allow(unsafe_op_in_unsafe_fn)]
+pub(crate) unsafe fn external_clock_lost() {
+    if !EXT_CLOCK_LOCKED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let bpm = INTERNAL_BPM.load(Ordering::Relaxed);
+    BPM.store(bpm, Ordering::Relaxed);
+    mark_dirty(DIRTY_BPM);
+    let (numer, denom) = step_interval_from_bpm(bpm);
+    store_step_interval(numer, denom);
+    // Don't leave the current step hanging while it waits for a pulse that won't come.
+    if PLAYING.load(Ordering::Relaxed) && EXT_STEPS_ALLOWED == 0 {
+        let tim3 = &*pac::TIM3::ptr();
+        tim3.sr().modify(|_, w| w.cc1if().clear_bit());
+        let cnt = tim3.cnt().read().cnt().bits();
+        start_next_step();
+        schedule_next_step_segment_from(cnt);
+    }
+}
+
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn advance_step_boundary() {
-    let step_us = get_next_step_interval_us();
-    STEP_US = step_us;
+    if EXT_CLOCK_LOCKED.load(Ordering::Relaxed) && PLAYING.load(Ordering::Relaxed) {
+        if EXT_STEPS_ALLOWED == 0 {
+            // Wait for the next clock pulse, keeping the elapsed time of the current step.
+            STEP_US = STEP_US.saturating_add(EXT_HOLD_US);
+            REMAINING_US = EXT_HOLD_US;
+            return;
+        }
+        EXT_STEPS_ALLOWED -= 1;
+    }
+    start_next_step();
+}
+
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn start_next_step() {
+    let mut step_us = get_next_step_interval_us();
     if PLAYING.load(Ordering::Relaxed) {
         let step = NEXT_STEP.load(Ordering::Relaxed);
-        CURRENT_STEP.store(step, Ordering::Relaxed);
-        STEP_FLAG.store(true, Ordering::Release);
+        if step == 0 {
+            let pending = PENDING_CACHE.swap(NO_CACHE, Ordering::AcqRel);
+            if pending != NO_CACHE {
+                ACTIVE_CACHE.store(pending, Ordering::Release);
+                if PATTERN_PENDING.swap(false, Ordering::Relaxed) {
+                    move_tracks(Transport::PatternSwitch);
+                    LFO_STEPS = [0; MAX_TRACKS];
+                    RESET_OUT_DUE = true;
+                }
+            }
+        }
         let cache_index = ACTIVE_CACHE.load(Ordering::Acquire);
         let cache = &RT_CACHE[cache_index as usize];
         // TODO: For now we just use the first track length. We can utilize different length
         // tracks in the future for polymetric things.
         let length = cache.lengths[0].min(MAX_STEPS as u8);
+        // `step` is where the pattern is, `audible` what is heard there.
+        let roll = &raw mut ROLL;
+        let roll_steps = ROLL_STEPS.load(Ordering::Relaxed);
+        if roll_steps != ROLL_REQUESTED {
+            ROLL_REQUESTED = roll_steps;
+            (*roll).start(roll_steps, step, length);
+        }
+        let audible = (*roll).audible_step(step, length);
+        POSITION_STEP = step;
+        let grid_us = step_us;
+        let previous_us = STEP_US;
+        step_us = swing_step_us(grid_us, step, cache);
+        STEP_US = step_us;
+        STEP_NOMINAL_US = step_us;
+        CLOCK_STEP_US = grid_us;
+        CURRENT_STEP.store(audible, Ordering::Relaxed);
+        STEP_FLAG.store(true, Ordering::Release);
+        let queued = QUEUED_SILENCED.load(Ordering::Acquire);
+        let due = match queued & !0xFF {
+            SILENCED_AT_BAR => SONG_POSITION.is_multiple_of(STEPS_PER_BAR),
+            SILENCED_AT_WRAP => step == 0,
+            _ => false,
+        };
+        if due {
+            QUEUED_SILENCED.store(0, Ordering::Release);
+            SILENCED_TRACKS.store(queued as u8, Ordering::Relaxed);
+        }
+        let fill_steps = FILL_STEPS_LEFT.load(Ordering::Relaxed);
+        FILL_STEPS_LEFT.store(fill_steps.saturating_sub(1), Ordering::Relaxed);
+        let fill = FILL_HELD.load(Ordering::Relaxed) || fill_steps != 0;
+        FILL_PLAYING.store(fill, Ordering::Release);
+        let song_step = SONG_POSITION;
+        SONG_POSITION = SONG_POSITION.wrapping_add(1) & 0x3FFF;
+        let ticks_per_step = CLOCK_TICKS_PER_STEP;
+        let swung = step_us != grid_us;
+        let pair_started = CLOCK_TICKS_END == 2 * ticks_per_step && CLOCK_PAIR_US == 0;
+        if swung && !step.is_multiple_of(2) && pair_started {
+            // The second step of a swung pair goes on with the first one's ticks and pulses.
+            CLOCK_PAIR_US = previous_us;
+            CLOCK_OUT_END_US = CLOCK_OUT_END_US.saturating_sub(previous_us);
+            RESET_OUT_END_US = RESET_OUT_END_US.saturating_sub(previous_us);
+        } else {
+            CLOCK_TICK_IN_STEP = 0;
+            CLOCK_TICKS_END = if swung { 2 * ticks_per_step } else { ticks_per_step };
+            CLOCK_PAIR_US = 0;
+            CLOCK_POSITION = song_step as u32 * ticks_per_step as u32;
+            CLOCK_OUT_END_US = 0;
+            RESET_OUT_END_US = 0;
+        }
         if length != 0 {
             NEXT_STEP.store((step + 1) % length, Ordering::Relaxed);
-            configure_gates_for_step(step, cache, step_us);
+            let positions = &raw mut POSITIONS;
+            // A roll plays `back` steps behind where every track is.
+            let back = (step + length - audible) % length;
+            let sound = playing_cache();
+            TRACK_STEPS = core::array::from_fn(|track_index| {
+                let position = (*positions).get(track_index);
+                sound.windows[track_index].step_at(sound.offsets[track_index], position, back)
+            });
+            (*positions).advance(&cache.reset_modes, 1, (step + 1) % length);
+            retrigger_held_notes(sound);
+            configure_gates_for_step(sound, step_us);
+            configure_cv_for_step(sound);
         } else {
             clear_gate_state();
         }
+    } else {
+        STEP_US = step_us;
+        STEP_NOMINAL_US = step_us;
+        CLOCK_STEP_US = step_us;
     }
     REMAINING_US = step_us;
 }
 
+/// Swing lengthens the first step of each pair and shortens the second by the same amount.
+/// The clock ticks stay on the unswung grid. Not applied while an external clock drives the
+/// steps, they follow the pulses.
+fn swing_step_us(step_us: u32, step: u8, cache: &RtCache) -> u32 {
+    if EXT_CLOCK_LOCKED.load(Ordering::Relaxed) {
+        return step_us;
+    }
+    let swing = cache.swing.clamp(MIN_SWING, MAX_SWING) as u32;
+    let percent = if step.is_multiple_of(2) { swing } else { 100 - swing };
+    (step_us as u64 * percent as u64 / 50) as u32
+}
+
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn catch_up_overrun(mut overrun: u32) -> u32 {
     while overrun != 0 {
@@ -634,6 +2161,9 @@ unsafe fn schedule_next_step_segment_from(base: u16) {
     let step_segment = step_segment as u16;
     remaining = remaining.saturating_sub(step_segment as u32);
     REMAINING_US = remaining;
+    SEGMENT_START = base;
+    SEGMENT_US = step_segment as u32;
+    SEGMENT_ELAPSED_US = elapsed;
 
     let next = base.wrapping_add(step_segment);
     LAST_CCR1 = next;
@@ -642,17 +2172,594 @@ unsafe fn schedule_next_step_segment_from(base: u16) {
 }
 
 pub fn select_step(seq: &mut SequencerState, step_index: u8) {
-    seq.prev_selected_step = seq.selected_step;
+    select_steps(seq, 1 << step_index);
     seq.selected_step = Some(step_index);
+}
+
+/// Moves editing to another pattern. The step selection is cleared rather than carried over.
+pub fn set_edit_pattern(seq: &mut SequencerState, pattern: u8) {
+    if pattern == seq.edit.pattern || pattern as usize >= MAX_PATTERNS {
+        return;
+    }
+    select_steps(seq, 0);
+    seq.selected_step = None;
+    seq.edit.pattern = pattern;
+    mark_dirty(DIRTY_PATTERN);
+}
+
+/// Called from the main loop. Shows the playing pattern while following playback, otherwise
+/// the pattern being edited.
+pub fn update_view(seq: &mut SequencerState) {
+    let pattern = if seq.follow { seq.playing_pattern_index() } else { seq.edit.pattern };
+    if pattern != seq.visible_pattern {
+        seq.visible_pattern = pattern;
+        mark_dirty(DIRTY_PATTERN);
+        mark_dirty_steps(u16::MAX);
+    }
+}
+
+/// Selects the steps from `from` to `to`, either way round. `to` becomes the selected step.
+pub fn select_step_range(seq: &mut SequencerState, from: u8, to: u8) {
+    let (low, high) = (from.min(to), from.max(to));
+    select_steps(seq, ((1u32 << (high + 1)) - (1u32 << low)) as u16);
+    seq.selected_step = Some(to);
+}
+
+fn select_steps(seq: &mut SequencerState, steps: u16) {
+    // Only the cells whose highlight changes need redrawing.
+    mark_dirty_cells(seq.edit.tracks, seq.edit.steps ^ steps);
+    seq.edit.steps = steps;
     mark_dirty(DIRTY_STEP_SELECTION);
 }
 
-pub fn set_step(sequencer_state: &mut SequencerState, tracks: u8, step_index: u8, pitch: u8) {
-    let pattern = &mut sequencer_state.patterns[sequencer_state.visible_pattern as usize];
+/// Applies `edit` to `steps` of `tracks` in the visible pattern.
+pub fn edit_steps(
+    sequencer_state: &mut SequencerState,
+    tracks: u8,
+    steps: u16,
+    edit: impl Fn(&mut Step),
+) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
     for track_index in iter_bits_u8(tracks) {
-        pattern.tracks[track_index as usize].steps[step_index as usize].pitch = pitch;
+        for step_index in iter_bits_u16(steps) {
+            edit(&mut pattern.tracks[track_index as usize].steps[step_index as usize]);
+        }
+    }
+    mark_dirty_cells(tracks, steps);
+    mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
+}
+
+pub fn set_step(
+    sequencer_state: &mut SequencerState,
+    tracks: u8,
+    steps: u16,
+    pitch: u8,
+    velocity: u8,
+) {
+    edit_steps(sequencer_state, tracks, steps, |step| {
+        step.pitch = pitch;
+        step.velocity = velocity.min(MAX_VELOCITY);
         // TODO: toggle active
-        pattern.tracks[track_index as usize].steps[step_index as usize].active = true;
+        step.active = true;
+        step.tie = false;
+    });
+}
+
+/// Sets every step of the selection to `value` of `field`, clamped to its range.
+pub fn set_step_field(
+    sequencer_state: &mut SequencerState,
+    tracks: u8,
+    steps: u16,
+    field: StepField,
+    value: u8,
+) {
+    edit_steps(sequencer_state, tracks, steps, |step| match field {
+        StepField::GateLength => step.gate_len = value.clamp(1, MAX_GATE_LENGTH),
+        StepField::Velocity => step.velocity = value.clamp(1, MAX_VELOCITY),
+        StepField::Probability => step.probability = value.min(MAX_PROBABILITY),
+    });
+}
+
+/// Resets the steps to empty ones.
+pub fn clear_steps(sequencer_state: &mut SequencerState, tracks: u8, steps: u16) {
+    edit_steps(sequencer_state, tracks, steps, |step| *step = Step::new());
+}
+
+/// Clears the step, for entering rests in step record.
+pub fn set_rest(sequencer_state: &mut SequencerState, tracks: u8, step_index: u8) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        let step = &mut pattern.tracks[track_index as usize].steps[step_index as usize];
+        step.active = false;
+        step.tie = false;
     }
     mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
 }
+
+/// Ties the step to the one before it, so the previous note keeps sounding.
+pub fn set_tie(sequencer_state: &mut SequencerState, tracks: u8, step_index: u8) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        let track = &mut pattern.tracks[track_index as usize];
+        let length = track.length.clamp(1, MAX_STEPS as u8);
+        let prev = (step_index + length - 1) % length;
+        let pitch = track.steps[prev as usize].pitch;
+        let step = &mut track.steps[step_index as usize];
+        step.active = true;
+        step.tie = true;
+        step.pitch = pitch;
+        step.micro = 0;
+    }
+    mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
+}
+
+/// Moves the selection to the next step of the first selected track, wrapping at its length.
+pub fn advance_selected_step(sequencer_state: &mut SequencerState) {
+    let Some(step) = sequencer_state.selected_step else {
+        return;
+    };
+    let first = sequencer_state.edit.tracks.trailing_zeros() as usize;
+    let pattern = &sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    let length = pattern.tracks[first.min(MAX_TRACKS - 1)].length.clamp(1, MAX_STEPS as u8);
+    select_step(sequencer_state, (step + 1) % length);
+}
+
+pub fn set_output_mode(sequencer_state: &mut SequencerState, tracks: u8, mode: OutputMode) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].output_mode = mode;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_trigger_ms(sequencer_state: &mut SequencerState, tracks: u8, trigger_ms: u8) {
+    let trigger_ms = trigger_ms.clamp(MIN_TRIGGER_MS, MAX_TRIGGER_MS);
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].trigger_ms = trigger_ms;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+/// Loops `tracks` over steps `start..=end`. Takes effect with the next step.
+pub fn set_loop_window(sequencer_state: &mut SequencerState, tracks: u8, start: u8, end: u8) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        let track = &mut pattern.tracks[track_index as usize];
+        track.loop_start = start.min(MAX_STEPS as u8 - 1);
+        track.loop_end = end.clamp(track.loop_start, MAX_STEPS as u8 - 1);
+    }
+    // The window brackets are drawn in the cells.
+    mark_dirty_cells(tracks, u16::MAX);
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+/// Starts `tracks` from step `offset` after their next reset.
+pub fn set_offset(sequencer_state: &mut SequencerState, tracks: u8, offset: u8) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].offset = offset.min(MAX_STEPS as u8 - 1);
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_reset_mode(sequencer_state: &mut SequencerState, tracks: u8, mode: ResetMode) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].reset_mode = mode;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_cv_mode(sequencer_state: &mut SequencerState, tracks: u8, mode: CvMode) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].cv_mode = mode;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_lfo(sequencer_state: &mut SequencerState, tracks: u8, lfo: Lfo) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].lfo = lfo;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_envelope(sequencer_state: &mut SequencerState, tracks: u8, envelope: Envelope) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        pattern.tracks[track_index as usize].envelope = envelope;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_clock_output(sequencer_state: &mut SequencerState, clock_out: ClockOutput) {
+    sequencer_state.settings.clock_out = clock_out;
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_reset_output(sequencer_state: &mut SequencerState, reset_out: ResetOutput) {
+    sequencer_state.settings.reset_out = reset_out;
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_midi_channel(sequencer_state: &mut SequencerState, tracks: u8, channel: u8) {
+    for track_index in iter_bits_u8(tracks) {
+        sequencer_state.settings.midi_channels[track_index as usize] = channel & 0x0F;
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_swing(sequencer_state: &mut SequencerState, swing: u8) {
+    sequencer_state.settings.swing = swing.clamp(MIN_SWING, MAX_SWING);
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_transpose(sequencer_state: &mut SequencerState, transpose: i8) {
+    sequencer_state.settings.transpose = transpose.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+    mark_dirty(DIRTY_RT_CACHE);
+}
+
+pub fn set_muted(sequencer_state: &mut SequencerState, tracks: u8, muted: bool) {
+    let mutes = sequencer_state.mutes_mut();
+    if muted {
+        mutes.muted |= tracks;
+    } else {
+        mutes.muted &= !tracks;
+    }
+    publish_mutes(sequencer_state, mute_change_at(sequencer_state));
+}
+
+pub fn set_soloed(sequencer_state: &mut SequencerState, tracks: u8, soloed: bool) {
+    let mutes = sequencer_state.mutes_mut();
+    if soloed {
+        mutes.soloed |= tracks;
+    } else {
+        mutes.soloed &= !tracks;
+    }
+    publish_mutes(sequencer_state, mute_change_at(sequencer_state));
+}
+
+fn mute_change_at(sequencer_state: &SequencerState) -> ChangeAt {
+    if sequencer_state.settings.quantize_mutes { ChangeAt::NextBar } else { ChangeAt::Now }
+}
+
+pub fn set_mute_scope(sequencer_state: &mut SequencerState, scope: MuteScope) {
+    sequencer_state.settings.mute_scope = scope;
+    publish_mutes(sequencer_state, ChangeAt::Now);
+}
+
+/// When a performance change reaches the outputs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeAt {
+    Now,
+    NextBar,
+    PatternEnd,
+}
+
+/// Hands the tracks to silence to the step timer. Stopped, there is nothing to wait for.
+pub fn publish_mutes(sequencer_state: &SequencerState, at: ChangeAt) {
+    let silenced = sequencer_state.mutes().silenced() as u16;
+    let playing = PLAYING.load(Ordering::Relaxed);
+    match at {
+        ChangeAt::NextBar if playing => {
+            QUEUED_SILENCED.store(SILENCED_AT_BAR | silenced, Ordering::Release);
+        }
+        ChangeAt::PatternEnd if playing => {
+            QUEUED_SILENCED.store(SILENCED_AT_WRAP | silenced, Ordering::Release);
+        }
+        _ => {
+            QUEUED_SILENCED.store(0, Ordering::Release);
+            SILENCED_TRACKS.store(silenced as u8, Ordering::Relaxed);
+        }
+    }
+    mark_dirty(DIRTY_MUTES);
+}
+
+/// Saves the mutes, solos and transpose in effect to scene `index`.
+pub fn save_scene(sequencer_state: &mut SequencerState, index: u8) {
+    let scene = Scene {
+        mutes: sequencer_state.mutes(),
+        transpose: sequencer_state.settings.transpose,
+    };
+    sequencer_state.scenes[index as usize] = Some(scene);
+}
+
+/// Recalls scene `index`, right away or with `at` `ChangeAt::PatternEnd` when the playing
+/// pattern wraps. Returns false if nothing was saved there.
+pub fn recall_scene(sequencer_state: &mut SequencerState, index: u8, at: ChangeAt) -> bool {
+    let Some(scene) = sequencer_state.scenes[index as usize] else {
+        return false;
+    };
+    *sequencer_state.mutes_mut() = scene.mutes;
+    sequencer_state.settings.transpose = scene.transpose;
+    publish_mutes(sequencer_state, at);
+    // Transpose is part of the step timer's cache, which can wait for the wrap as well.
+    if at == ChangeAt::PatternEnd && PLAYING.load(Ordering::Relaxed) {
+        rebuild_rt_cache_at_wrap(sequencer_state);
+    } else {
+        mark_dirty(DIRTY_RT_CACHE);
+    }
+    true
+}
+
+/// Switches right away when stopped, otherwise when the playing pattern wraps. See
+/// `update_queued_pattern()`.
+pub fn queue_pattern(sequencer_state: &mut SequencerState, pattern: u8) {
+    if pattern as usize >= MAX_PATTERNS {
+        return;
+    }
+    if PLAYING.load(Ordering::Relaxed) {
+        sequencer_state.queued_pattern = Some(pattern);
+    } else {
+        sequencer_state.queued_pattern = None;
+        sequencer_state.playing_pattern = pattern;
+        publish_mutes(sequencer_state, ChangeAt::Now);
+        mark_dirty(DIRTY_PATTERN | DIRTY_RT_CACHE);
+    }
+}
+
+/// Called from the main loop. Once the last step of the pattern is playing, the queued
+/// pattern's cache is built and handed to the step timer for the wrap. If the main loop gets
+/// there too late, the switch happens on the wrap after.
+pub fn update_queued_pattern(sequencer_state: &mut SequencerState) {
+    let Some(pattern) = sequencer_state.queued_pattern else {
+        return;
+    };
+    let playing = PLAYING.load(Ordering::Relaxed);
+    if playing && NEXT_STEP.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    sequencer_state.queued_pattern = None;
+    sequencer_state.playing_pattern = pattern;
+    // Per pattern mutes change with the pattern.
+    publish_mutes(sequencer_state, ChangeAt::PatternEnd);
+    if playing {
+        PATTERN_PENDING.store(true, Ordering::Relaxed);
+        rebuild_rt_cache_at_wrap(sequencer_state);
+        mark_dirty(DIRTY_PATTERN);
+    } else {
+        mark_dirty(DIRTY_PATTERN | DIRTY_RT_CACHE);
+    }
+}
+
+/// Binds a CC to `param`, replacing an existing binding of the same CC. Returns false when
+/// all binding slots are taken.
+pub fn bind_cc(
+    sequencer_state: &mut SequencerState,
+    channel: u8,
+    control: u8,
+    param: Param,
+) -> bool {
+    let bindings = &mut sequencer_state.settings.cc_bindings;
+    let slot = bindings
+        .iter()
+        .position(|b| matches!(b, Some(b) if b.channel == channel && b.control == control))
+        .or_else(|| bindings.iter().position(|b| b.is_none()));
+    match slot {
+        Some(index) => {
+            bindings[index] = Some(CcBinding { channel, control, param });
+            true
+        }
+        None => false,
+    }
+}
+
+/// Records a hit at the playhead on `tracks` of the playing pattern, keeping the pitch that is
+/// already there. Each track records at its own step. Returns where they landed.
+pub fn record_hit(sequencer_state: &mut SequencerState, tracks: u8) -> [u8; MAX_TRACKS] {
+    record_steps(sequencer_state, tracks, |_| {})
+}
+
+/// Like `record_hit()`, setting the note as well.
+pub fn record_note(
+    sequencer_state: &mut SequencerState,
+    tracks: u8,
+    pitch: u8,
+    velocity: u8,
+) -> [u8; MAX_TRACKS] {
+    record_steps(sequencer_state, tracks, |step| {
+        step.pitch = pitch;
+        step.velocity = velocity.min(MAX_VELOCITY);
+    })
+}
+
+fn record_steps(
+    sequencer_state: &mut SequencerState,
+    tracks: u8,
+    mut edit: impl FnMut(&mut Step),
+) -> [u8; MAX_TRACKS] {
+    let (steps, micro) = record_position(sequencer_state.record_micro);
+    let pattern_index = sequencer_state.playing_pattern_index();
+    let pattern = &mut sequencer_state.patterns[pattern_index as usize];
+    for track_index in iter_bits_u8(tracks) {
+        let step_index = steps[track_index as usize];
+        let step = &mut pattern.tracks[track_index as usize].steps[step_index as usize];
+        edit(step);
+        step.active = true;
+        step.tie = false;
+        step.micro = micro;
+        sequencer_state.recorded_steps[track_index as usize] |= 1 << step_index;
+        mark_dirty_cells(1 << track_index, 1 << step_index);
+    }
+    mark_dirty(DIRTY_RT_CACHE);
+    steps
+}
+
+/// Called from the main loop when the playhead moves. In replace mode, clears the upcoming step
+/// of each selected track unless something was recorded on it during this pass.
+pub fn update_replace_record(sequencer_state: &mut SequencerState) {
+    if sequencer_state.record != RecordMode::Replace || !PLAYING.load(Ordering::Relaxed) {
+        sequencer_state.recorded_steps = [0; MAX_TRACKS];
+        return;
+    }
+    let steps = upcoming_track_steps();
+    let pattern_index = sequencer_state.playing_pattern_index();
+    let before = sequencer_state.patterns[pattern_index as usize];
+    let mut cleared = false;
+    for track_index in iter_bits_u8(sequencer_state.edit.tracks) {
+        let step_index = steps[track_index as usize];
+        let bit = 1u16 << step_index;
+        let recorded = &mut sequencer_state.recorded_steps[track_index as usize];
+        if *recorded & bit != 0 {
+            *recorded &= !bit;
+            continue;
+        }
+        let pattern = &mut sequencer_state.patterns[pattern_index as usize];
+        let step = &mut pattern.tracks[track_index as usize].steps[step_index as usize];
+        step.active = false;
+        step.tie = false;
+        step.micro = 0;
+        mark_dirty_cells(1 << track_index, bit);
+        cleared = true;
+    }
+    if cleared {
+        record_edit(sequencer_state, pattern_index, &before);
+        mark_dirty(DIRTY_RT_CACHE);
+    }
+}
+
+pub fn set_record_mode(sequencer_state: &mut SequencerState, mode: RecordMode) {
+    sequencer_state.record = mode;
+    sequencer_state.recorded_steps = [0; MAX_TRACKS];
+    mark_dirty(DIRTY_RECORD);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(entries: &[u8]) -> Song {
+        let mut song = Song::new();
+        song.entries[..entries.len()].copy_from_slice(entries);
+        song.length = entries.len() as u8;
+        song
+    }
+
+    #[test]
+    fn song_position_lands_in_its_entry() {
+        // Pattern 1 is 8 steps, the others 16.
+        let song = song(&[0, 1, 0, 2]);
+        let length_of = |pattern: u8| if pattern == 1 { 8 } else { 16 };
+        assert_eq!(song.locate(0, length_of), Some((0, 0)));
+        assert_eq!(song.locate(15, length_of), Some((0, 15)));
+        assert_eq!(song.locate(16, length_of), Some((1, 0)));
+        assert_eq!(song.locate(23, length_of), Some((1, 7)));
+        assert_eq!(song.locate(24, length_of), Some((2, 0)));
+        assert_eq!(song.locate(45, length_of), Some((3, 5)));
+        // 56 steps in all, then it loops.
+        assert_eq!(song.locate(56 + 17, length_of), Some((1, 1)));
+    }
+
+    #[test]
+    fn empty_song_has_no_position() {
+        assert_eq!(Song::new().locate(5, |_| 16), None);
+    }
+
+    // Ticks the envelope from `stage` until it moves on. Checks that took `ms` worth of ticks,
+    // give or take one for rounding, and returns where it went.
+    fn run_stage(envelope: &Envelope, stage: EnvStage, level: &mut u32, ms: u32) -> EnvStage {
+        let mut ticks = 0u32;
+        loop {
+            let (next, next_level) = envelope_tick(envelope, stage, *level, ENV_MAX_LEVEL);
+            ticks += 1;
+            *level = next_level;
+            if next != stage {
+                let expected = (ms * 1000 / CV_TICK_US as u32).max(1);
+                assert!(ticks.abs_diff(expected) <= 1, "{stage:?} took {ticks} ticks");
+                return next;
+            }
+            assert!(ticks < 10_000, "{stage:?} never ends");
+        }
+    }
+
+    #[test]
+    fn adsr_holds_at_sustain_until_released() {
+        let envelope = Envelope {
+            attack_ms: 10,
+            decay_ms: 20,
+            sustain: 50,
+            release_ms: 40,
+            ..Envelope::new()
+        };
+        let mut level = 0;
+        assert_eq!(run_stage(&envelope, EnvStage::Attack, &mut level, 10), EnvStage::Decay);
+        assert_eq!(level, ENV_MAX_LEVEL);
+        // Decay and release times are for the whole range, so half way takes half the time.
+        assert_eq!(run_stage(&envelope, EnvStage::Decay, &mut level, 10), EnvStage::Sustain);
+        assert_eq!(level, ENV_MAX_LEVEL / 100 * 50);
+        let held = envelope_tick(&envelope, EnvStage::Sustain, level, ENV_MAX_LEVEL);
+        assert_eq!(held, (EnvStage::Sustain, level));
+        assert_eq!(run_stage(&envelope, EnvStage::Release, &mut level, 20), EnvStage::Idle);
+        assert_eq!(level, 0);
+    }
+
+    #[test]
+    fn ad_decays_to_idle() {
+        let envelope = Envelope {
+            mode: EnvelopeMode::Ad,
+            attack_ms: 0,
+            decay_ms: 5,
+            ..Envelope::new()
+        };
+        let mut level = 0;
+        assert_eq!(run_stage(&envelope, EnvStage::Attack, &mut level, 0), EnvStage::Decay);
+        assert_eq!(run_stage(&envelope, EnvStage::Decay, &mut level, 5), EnvStage::Idle);
+        assert_eq!(level, 0);
+    }
+
+    #[test]
+    fn advance_wraps_at_the_first_selected_track_length() {
+        let mut state = SequencerState::new();
+        state.patterns[0].tracks[2].length = 6;
+        state.edit.tracks = 0b0000_1100;
+        select_step(&mut state, 4);
+        advance_selected_step(&mut state);
+        assert_eq!(state.selected_step, Some(5));
+        advance_selected_step(&mut state);
+        assert_eq!((state.selected_step, state.edit.steps), (Some(0), 1));
+        // A 16 step track goes on past 5.
+        state.edit.tracks = 0b0000_1000;
+        select_step(&mut state, 5);
+        advance_selected_step(&mut state);
+        assert_eq!(state.selected_step, Some(6));
+    }
+
+    #[test]
+    fn chained_ties_carry_the_pitch() {
+        let mut state = SequencerState::new();
+        set_step(&mut state, 1, 1 << 3, 60, DEFAULT_VELOCITY);
+        set_tie(&mut state, 1, 4);
+        set_tie(&mut state, 1, 5);
+        let steps = &state.patterns[0].tracks[0].steps;
+        for step in &steps[4..=5] {
+            assert!(step.active && step.tie);
+            assert_eq!(step.pitch, 60);
+        }
+        assert!(!steps[3].tie);
+    }
+
+    #[test]
+    fn tie_on_the_first_step_wraps_to_the_last() {
+        let mut state = SequencerState::new();
+        state.patterns[0].tracks[1].length = 8;
+        set_step(&mut state, 0b11, 1 << 7, 48, DEFAULT_VELOCITY);
+        set_step(&mut state, 0b01, 1 << 15, 72, DEFAULT_VELOCITY);
+        set_tie(&mut state, 0b11, 0);
+        // Each track ties to the last step within its own length.
+        assert_eq!(state.patterns[0].tracks[0].steps[0].pitch, 72);
+        assert_eq!(state.patterns[0].tracks[1].steps[0].pitch, 48);
+    }
+
+    #[test]
+    fn lfo_phase_covers_the_step_share_of_the_cycle() {
+        for (rate, step_us) in [(1, 20_000), (4, 125_000), (64, 600_000)] {
+            let lfo = Lfo { rate, ..Lfo::new() };
+            let moved = lfo_phase_per_us(lfo, step_us).wrapping_mul(step_us);
+            let span = ((1u64 << 32) / rate as u64) as u32;
+            assert!(span.wrapping_sub(moved) < step_us, "rate {rate}");
+        }
+    }
+}
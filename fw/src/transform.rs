@@ -0,0 +1,224 @@
+use crate::sequencer::{
+    mark_dirty, mark_dirty_cells, SequencerState, Step, Track, DIRTY_NOTE_DATA, DIRTY_PATTERN,
+    DIRTY_RT_CACHE, MAX_STEPS,
+};
+use crate::utils::iter_bits_u8;
+
+const MAX_PITCH: u8 = 127;
+/// Scale used by degree transposition, semitones from C.
+pub const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transform {
+    RotateLeft,
+    RotateRight,
+    Reverse,
+    Invert,
+    Transpose(i8),
+    TransposeDegrees(i8),
+    DoubleLength,
+    HalveLength,
+}
+
+impl Transform {
+    pub fn apply(self, track: &mut Track) {
+        match self {
+            Transform::RotateLeft => rotate_left(track),
+            Transform::RotateRight => rotate_right(track),
+            Transform::Reverse => reverse(track),
+            Transform::Invert => invert(track),
+            Transform::Transpose(semitones) => transpose(track, semitones),
+            Transform::TransposeDegrees(degrees) => {
+                transpose_degrees(track, degrees, &MAJOR_SCALE)
+            }
+            Transform::DoubleLength => double_length(track),
+            Transform::HalveLength => halve_length(track),
+        }
+    }
+}
+
+/// Applies `transform` to `tracks` of the visible pattern.
+pub fn transform_tracks(sequencer_state: &mut SequencerState, tracks: u8, transform: Transform) {
+    let pattern = &mut sequencer_state.patterns[sequencer_state.edit.pattern as usize];
+    for track_index in iter_bits_u8(tracks) {
+        transform.apply(&mut pattern.tracks[track_index as usize]);
+    }
+    if matches!(transform, Transform::DoubleLength | Transform::HalveLength) {
+        mark_dirty(DIRTY_PATTERN);
+    }
+    mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
+    mark_dirty_cells(tracks, u16::MAX);
+}
+
+fn played_steps(track: &mut Track) -> &mut [Step] {
+    let length = (track.length as usize).clamp(1, MAX_STEPS);
+    &mut track.steps[..length]
+}
+
+/// Moves every step one earlier, the first step wraps to the end of the track.
+pub fn rotate_left(track: &mut Track) {
+    played_steps(track).rotate_left(1);
+}
+
+/// Moves every step one later, the last step wraps to the start of the track.
+pub fn rotate_right(track: &mut Track) {
+    played_steps(track).rotate_right(1);
+}
+
+pub fn reverse(track: &mut Track) {
+    played_steps(track).reverse();
+}
+
+/// Swaps active and inactive steps. Ties are dropped, since the notes they held change.
+pub fn invert(track: &mut Track) {
+    for step in played_steps(track) {
+        step.active = !step.active;
+        step.tie = false;
+    }
+}
+
+/// Shifts every pitch by `semitones`, clamped to the MIDI note range.
+pub fn transpose(track: &mut Track, semitones: i8) {
+    for step in &mut track.steps {
+        step.pitch = (step.pitch as i16 + semitones as i16).clamp(0, MAX_PITCH as i16) as u8;
+    }
+}
+
+/// Shifts every pitch by `degrees` steps of `scale`. Notes outside the scale keep their
+/// distance from the scale note below them.
+pub fn transpose_degrees(track: &mut Track, degrees: i8, scale: &[u8]) {
+    for step in &mut track.steps {
+        step.pitch = shift_degrees(step.pitch, degrees, scale);
+    }
+}
+
+fn shift_degrees(pitch: u8, degrees: i8, scale: &[u8]) -> u8 {
+    let len = scale.len() as i16;
+    let class = pitch % 12;
+    let index = scale.iter().rposition(|&note| note <= class).unwrap_or(0);
+    let offset = class - scale[index];
+    let degree = (pitch / 12) as i16 * len + index as i16 + degrees as i16;
+    let octave = degree.div_euclid(len);
+    let note = scale[degree.rem_euclid(len) as usize] + offset;
+    (octave * 12 + note as i16).clamp(0, MAX_PITCH as i16) as u8
+}
+
+/// Repeats the played steps once. Does nothing if the result wouldn't fit.
+pub fn double_length(track: &mut Track) {
+    let length = track.length as usize;
+    if length == 0 || length * 2 > MAX_STEPS {
+        return;
+    }
+    track.steps.copy_within(..length, length);
+    track.length *= 2;
+}
+
+/// Keeps every other step. Steps past the new length are left as they were.
+pub fn halve_length(track: &mut Track) {
+    let length = track.length as usize / 2;
+    if length == 0 {
+        return;
+    }
+    for index in 0..length {
+        track.steps[index] = track.steps[index * 2];
+    }
+    track.length = length as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(active: &[usize], length: u8) -> Track {
+        let mut track = Track::new();
+        for (index, step) in track.steps.iter_mut().enumerate() {
+            step.pitch = index as u8;
+            step.active = active.contains(&index);
+        }
+        track.length = length;
+        track
+    }
+
+    fn pitches(track: &Track) -> [u8; MAX_STEPS] {
+        track.steps.map(|step| step.pitch)
+    }
+
+    #[test]
+    fn rotate_stays_within_length() {
+        let mut t = track(&[0], 4);
+        rotate_left(&mut t);
+        assert_eq!(&pitches(&t)[..5], &[1, 2, 3, 0, 4]);
+        assert!(t.steps[3].active);
+        rotate_right(&mut t);
+        rotate_right(&mut t);
+        assert_eq!(&pitches(&t)[..5], &[3, 0, 1, 2, 4]);
+        assert!(t.steps[1].active);
+    }
+
+    #[test]
+    fn reverse_within_length() {
+        let mut t = track(&[0], 3);
+        reverse(&mut t);
+        assert_eq!(&pitches(&t)[..4], &[2, 1, 0, 3]);
+        assert!(t.steps[2].active);
+    }
+
+    #[test]
+    fn invert_within_length() {
+        let mut t = track(&[1], 3);
+        t.steps[1].tie = true;
+        invert(&mut t);
+        let active = t.steps.map(|step| step.active);
+        assert_eq!(&active[..4], &[true, false, true, false]);
+        assert!(!t.steps[1].tie);
+    }
+
+    #[test]
+    fn transpose_clamps() {
+        let mut t = track(&[], 16);
+        t.steps[0].pitch = 125;
+        transpose(&mut t, 5);
+        assert_eq!(t.steps[0].pitch, 127);
+        assert_eq!(t.steps[1].pitch, 6);
+        transpose(&mut t, -7);
+        assert_eq!(t.steps[1].pitch, 0);
+        assert_eq!(t.steps[0].pitch, 120);
+    }
+
+    #[test]
+    fn degrees_follow_the_scale() {
+        let shift = |pitch, degrees| shift_degrees(pitch, degrees, &MAJOR_SCALE);
+        assert_eq!(shift(60, 1), 62);
+        assert_eq!(shift(64, 1), 65);
+        assert_eq!(shift(71, 1), 72);
+        assert_eq!(shift(60, -1), 59);
+        assert_eq!(shift(60, 7), 72);
+        assert_eq!(shift(60, 2), 64);
+        // C#, between C and D, moves to D#.
+        assert_eq!(shift(61, 1), 63);
+        assert_eq!(shift(0, -1), 0);
+        assert_eq!(shift(127, 3), 127);
+    }
+
+    #[test]
+    fn double_and_halve() {
+        let mut t = track(&[0, 2], 4);
+        double_length(&mut t);
+        assert_eq!(t.length, 8);
+        assert_eq!(&pitches(&t)[..9], &[0, 1, 2, 3, 0, 1, 2, 3, 8]);
+        assert!(t.steps[4].active && t.steps[6].active);
+
+        halve_length(&mut t);
+        halve_length(&mut t);
+        assert_eq!(t.length, 2);
+        assert_eq!(&pitches(&t)[..2], &[0, 0]);
+        assert!(t.steps[0].active && t.steps[1].active);
+
+        let mut full = track(&[], 16);
+        double_length(&mut full);
+        assert_eq!(full.length, 16);
+        let mut single = track(&[], 1);
+        halve_length(&mut single);
+        assert_eq!(single.length, 1);
+    }
+}
@@ -0,0 +1,470 @@
+use crate::playhead::ResetMode;
+use crate::sequencer::{
+    mark_dirty, mark_dirty_cells, publish_mutes, set_edit_pattern, ChangeAt, CvMode, Envelope,
+    Lfo, Mutes, OutputMode, Pattern, SequencerState, Step, Track, DIRTY_NOTE_DATA,
+    DIRTY_PATTERN, DIRTY_RT_CACHE, MAX_STEPS, MAX_TRACKS,
+};
+
+// About 40 bytes per entry. An edit of every step and setting of every track and the mutes
+// takes 137 entries, so this always holds at least one full pattern edit, like a paste.
+pub const HISTORY_LEN: usize = 256;
+
+#[derive(Clone, Copy)]
+enum Change {
+    Step { index: u8, before: Step, after: Step },
+    Header { before: Header, after: Header },
+    // For the whole pattern, `Entry::track` is unused.
+    Mutes { before: Mutes, after: Mutes },
+    // The mutes of the global mute scope, `Entry::pattern` and `Entry::track` are unused.
+    GlobalMutes { before: Mutes, after: Mutes },
+}
+
+/// Everything about a track but its steps.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Header {
+    length: u8,
+    loop_start: u8,
+    loop_end: u8,
+    offset: u8,
+    reset_mode: ResetMode,
+    output_mode: OutputMode,
+    trigger_ms: u8,
+    cv_mode: CvMode,
+    lfo: Lfo,
+    envelope: Envelope,
+}
+
+impl Header {
+    fn of(track: &Track) -> Self {
+        // No `..` here or in `apply()`, so a new field fails to build until it's handled.
+        let Track {
+            steps: _,
+            length,
+            loop_start,
+            loop_end,
+            offset,
+            reset_mode,
+            output_mode,
+            trigger_ms,
+            cv_mode,
+            lfo,
+            envelope,
+        } = *track;
+        Self {
+            length,
+            loop_start,
+            loop_end,
+            offset,
+            reset_mode,
+            output_mode,
+            trigger_ms,
+            cv_mode,
+            lfo,
+            envelope,
+        }
+    }
+
+    /// Sets the fields where `self` and `other` differ, the ones the edit changed. The rest keep
+    /// what later changes left there, such as a CC turned since.
+    fn apply(self, other: Self, track: &mut Track) {
+        fn set<T: Copy + PartialEq>(field: &mut T, to: T, other: T) {
+            if to != other {
+                *field = to;
+            }
+        }
+        let Track {
+            steps: _,
+            length,
+            loop_start,
+            loop_end,
+            offset,
+            reset_mode,
+            output_mode,
+            trigger_ms,
+            cv_mode,
+            lfo: Lfo { shape, rate },
+            envelope: Envelope { mode, attack_ms, decay_ms, sustain, release_ms },
+        } = track;
+        set(length, self.length, other.length);
+        set(loop_start, self.loop_start, other.loop_start);
+        set(loop_end, self.loop_end, other.loop_end);
+        set(offset, self.offset, other.offset);
+        set(reset_mode, self.reset_mode, other.reset_mode);
+        set(output_mode, self.output_mode, other.output_mode);
+        set(trigger_ms, self.trigger_ms, other.trigger_ms);
+        set(cv_mode, self.cv_mode, other.cv_mode);
+        set(shape, self.lfo.shape, other.lfo.shape);
+        set(rate, self.lfo.rate, other.lfo.rate);
+        let (to, from) = (self.envelope, other.envelope);
+        set(mode, to.mode, from.mode);
+        set(attack_ms, to.attack_ms, from.attack_ms);
+        set(decay_ms, to.decay_ms, from.decay_ms);
+        set(sustain, to.sustain, from.sustain);
+        set(release_ms, to.release_ms, from.release_ms);
+    }
+}
+
+/// Sets the tracks whose mute or solo the edit changed, keeping later changes to the others.
+fn apply_mutes(mutes: &mut Mutes, to: Mutes, other: Mutes) {
+    let muted = to.muted ^ other.muted;
+    let soloed = to.soloed ^ other.soloed;
+    mutes.muted = (mutes.muted & !muted) | (to.muted & muted);
+    mutes.soloed = (mutes.soloed & !soloed) | (to.soloed & soloed);
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    // Entries of one edit share a group and are undone together.
+    group: u16,
+    pattern: u8,
+    track: u8,
+    change: Change,
+}
+
+const EMPTY_ENTRY: Entry = Entry {
+    group: 0,
+    pattern: 0,
+    track: 0,
+    change: Change::Mutes { before: Mutes::new(), after: Mutes::new() },
+};
+
+/// Ring buffer of pattern edits, oldest first. Entries past `applied` were undone and are
+/// what redo replays; a new edit drops them. When full, the oldest edit is dropped.
+pub struct History {
+    entries: [Entry; HISTORY_LEN],
+    start: usize,
+    len: usize,
+    applied: usize,
+    next_group: u16,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self {
+            entries: [EMPTY_ENTRY; HISTORY_LEN],
+            start: 0,
+            len: 0,
+            applied: 0,
+            next_group: 0,
+        }
+    }
+
+    fn entry(&self, n: usize) -> Entry {
+        self.entries[(self.start + n) % HISTORY_LEN]
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.len = self.applied;
+        if self.len == HISTORY_LEN {
+            let oldest = self.entries[self.start].group;
+            while self.len > 0 && self.entries[self.start].group == oldest {
+                self.start = (self.start + 1) % HISTORY_LEN;
+                self.len -= 1;
+            }
+        }
+        self.entries[(self.start + self.len) % HISTORY_LEN] = entry;
+        self.len += 1;
+        self.applied = self.len;
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What an edit from the controls may change, taken before it: the pattern being edited, the
+/// playing one that live recording writes to, and the global mutes.
+pub struct Snapshot {
+    edit_index: u8,
+    edit: Pattern,
+    playing_index: u8,
+    playing: Pattern,
+    mutes: Mutes,
+}
+
+impl Snapshot {
+    pub fn take(sequencer_state: &SequencerState) -> Self {
+        let edit_index = sequencer_state.edit.pattern;
+        let playing_index = sequencer_state.playing_pattern_index();
+        Self {
+            edit_index,
+            edit: sequencer_state.patterns[edit_index as usize],
+            playing_index,
+            playing: sequencer_state.patterns[playing_index as usize],
+            mutes: sequencer_state.mutes,
+        }
+    }
+}
+
+/// Records everything that changed since `before` was taken as one undoable edit.
+pub fn record_changes(sequencer_state: &mut SequencerState, before: &Snapshot) {
+    let mut changed = record_pattern(sequencer_state, before.edit_index, &before.edit);
+    if before.playing_index != before.edit_index {
+        changed |= record_pattern(sequencer_state, before.playing_index, &before.playing);
+    }
+    let after = sequencer_state.mutes;
+    if before.mutes != after {
+        let change = Change::GlobalMutes { before: before.mutes, after };
+        let group = sequencer_state.history.next_group;
+        sequencer_state.history.push(Entry { group, pattern: 0, track: 0, change });
+        changed = true;
+    }
+    end_group(&mut sequencer_state.history, changed);
+}
+
+/// Records the difference between `before` and the current state of pattern `pattern_index`
+/// as one undoable edit. Call with a copy of the pattern taken before the edit.
+pub fn record_edit(sequencer_state: &mut SequencerState, pattern_index: u8, before: &Pattern) {
+    let changed = record_pattern(sequencer_state, pattern_index, before);
+    end_group(&mut sequencer_state.history, changed);
+}
+
+fn end_group(history: &mut History, changed: bool) {
+    if changed {
+        history.next_group = history.next_group.wrapping_add(1);
+    }
+}
+
+/// Adds the changes to pattern `pattern_index` to the edit being recorded.
+fn record_pattern(
+    sequencer_state: &mut SequencerState,
+    pattern_index: u8,
+    before: &Pattern,
+) -> bool {
+    let after = &sequencer_state.patterns[pattern_index as usize];
+    let history = &mut sequencer_state.history;
+    let group = history.next_group;
+    let mut changed = false;
+    for track in 0..MAX_TRACKS {
+        let (old, new) = (&before.tracks[track], &after.tracks[track]);
+        let entry = |change| Entry { group, pattern: pattern_index, track: track as u8, change };
+        for index in 0..MAX_STEPS {
+            if old.steps[index] != new.steps[index] {
+                history.push(entry(Change::Step {
+                    index: index as u8,
+                    before: old.steps[index],
+                    after: new.steps[index],
+                }));
+                changed = true;
+            }
+        }
+        let (old, new) = (Header::of(old), Header::of(new));
+        if old != new {
+            history.push(entry(Change::Header { before: old, after: new }));
+            changed = true;
+        }
+    }
+    if before.mutes != after.mutes {
+        let change = Change::Mutes { before: before.mutes, after: after.mutes };
+        history.push(Entry { group, pattern: pattern_index, track: 0, change });
+        changed = true;
+    }
+    changed
+}
+
+/// Reverts the last edit. Returns false when there is nothing left to undo.
+pub fn undo(sequencer_state: &mut SequencerState) -> bool {
+    let history = &sequencer_state.history;
+    if history.applied == 0 {
+        return false;
+    }
+    let group = history.entry(history.applied - 1).group;
+    while sequencer_state.history.applied > 0 {
+        let entry = sequencer_state.history.entry(sequencer_state.history.applied - 1);
+        if entry.group != group {
+            break;
+        }
+        restore(sequencer_state, entry, false);
+        sequencer_state.history.applied -= 1;
+    }
+    true
+}
+
+/// Replays the last undone edit. Returns false when there is nothing to redo.
+pub fn redo(sequencer_state: &mut SequencerState) -> bool {
+    let history = &sequencer_state.history;
+    if history.applied == history.len {
+        return false;
+    }
+    let group = history.entry(history.applied).group;
+    while sequencer_state.history.applied < sequencer_state.history.len {
+        let entry = sequencer_state.history.entry(sequencer_state.history.applied);
+        if entry.group != group {
+            break;
+        }
+        restore(sequencer_state, entry, true);
+        sequencer_state.history.applied += 1;
+    }
+    true
+}
+
+/// Applies one side of an entry. Restoring into a pattern other than the one being edited
+/// switches editing to it, so the change can be seen. Within the same pattern the step
+/// selection stays.
+fn restore(sequencer_state: &mut SequencerState, entry: Entry, redo: bool) {
+    let global = matches!(entry.change, Change::GlobalMutes { .. });
+    if !global && entry.pattern != sequencer_state.edit.pattern {
+        set_edit_pattern(sequencer_state, entry.pattern);
+    }
+    let pattern = &mut sequencer_state.patterns[entry.pattern as usize];
+    let track = &mut pattern.tracks[entry.track as usize];
+    match entry.change {
+        Change::Step { index, before, after } => {
+            track.steps[index as usize] = if redo { after } else { before };
+            mark_dirty_cells(1 << entry.track, 1 << index);
+        }
+        Change::Header { before, after } => {
+            let (to, other) = if redo { (after, before) } else { (before, after) };
+            to.apply(other, track);
+            // Length and loop window show across the row.
+            mark_dirty_cells(1 << entry.track, u16::MAX);
+            mark_dirty(DIRTY_PATTERN);
+        }
+        Change::Mutes { before, after } => {
+            let (to, other) = if redo { (after, before) } else { (before, after) };
+            apply_mutes(&mut pattern.mutes, to, other);
+            publish_mutes(sequencer_state, ChangeAt::Now);
+        }
+        Change::GlobalMutes { before, after } => {
+            let (to, other) = if redo { (after, before) } else { (before, after) };
+            apply_mutes(&mut sequencer_state.mutes, to, other);
+            publish_mutes(sequencer_state, ChangeAt::Now);
+        }
+    }
+    mark_dirty(DIRTY_NOTE_DATA | DIRTY_RT_CACHE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::LfoShape;
+
+    // Applies `change` to pattern 0 and records it as one edit.
+    fn edit(state: &mut SequencerState, change: impl FnOnce(&mut Pattern)) {
+        let before = state.patterns[0];
+        change(&mut state.patterns[0]);
+        record_edit(state, 0, &before);
+    }
+
+    fn set_pitch(state: &mut SequencerState, track: usize, step: usize, pitch: u8) {
+        edit(state, |pattern| pattern.tracks[track].steps[step].pitch = pitch);
+    }
+
+    fn pitch(state: &SequencerState, track: usize, step: usize) -> u8 {
+        state.patterns[0].tracks[track].steps[step].pitch
+    }
+
+    #[test]
+    fn group_across_tracks_is_undone_in_one_step() {
+        let mut state = SequencerState::new();
+        edit(&mut state, |pattern| {
+            pattern.tracks[0].steps[0].pitch = 60;
+            pattern.tracks[1].steps[5].pitch = 62;
+            pattern.tracks[2].loop_end = 7;
+        });
+        assert!(undo(&mut state));
+        assert_eq!((pitch(&state, 0, 0), pitch(&state, 1, 5)), (0, 0));
+        assert_eq!(state.patterns[0].tracks[2].loop_end, MAX_STEPS as u8 - 1);
+        assert!(!undo(&mut state));
+        assert!(redo(&mut state));
+        assert_eq!((pitch(&state, 0, 0), pitch(&state, 1, 5)), (60, 62));
+        assert_eq!(state.patterns[0].tracks[2].loop_end, 7);
+    }
+
+    #[test]
+    fn settings_and_mutes_are_undone() {
+        let mut state = SequencerState::new();
+        let track = state.patterns[0].tracks[3];
+        edit(&mut state, |pattern| {
+            let track = &mut pattern.tracks[3];
+            track.offset = 4;
+            track.cv_mode = CvMode::Lfo;
+            track.lfo.shape = LfoShape::Square;
+            track.envelope.attack_ms = 250;
+            pattern.mutes.muted = 0b100;
+        });
+        assert!(undo(&mut state));
+        assert!(Header::of(&state.patterns[0].tracks[3]) == Header::of(&track));
+        assert_eq!(state.patterns[0].mutes, Mutes::new());
+        assert!(redo(&mut state));
+        assert_eq!(state.patterns[0].tracks[3].offset, 4);
+        assert_eq!(state.patterns[0].mutes.muted, 0b100);
+    }
+
+    #[test]
+    fn new_edit_clears_redo() {
+        let mut state = SequencerState::new();
+        set_pitch(&mut state, 0, 0, 60);
+        set_pitch(&mut state, 0, 1, 61);
+        assert!(undo(&mut state));
+        set_pitch(&mut state, 0, 2, 62);
+        assert!(!redo(&mut state));
+        assert_eq!(pitch(&state, 0, 1), 0);
+        assert!(undo(&mut state));
+        assert!(undo(&mut state));
+        assert_eq!((pitch(&state, 0, 0), pitch(&state, 0, 2)), (0, 0));
+        assert!(!undo(&mut state));
+    }
+
+    #[test]
+    fn full_history_drops_the_oldest_group() {
+        let mut state = SequencerState::new();
+        // Three entries, then enough single entry edits to need their room.
+        edit(&mut state, |pattern| {
+            for track in 0..3 {
+                pattern.tracks[track].steps[0].pitch = 50;
+            }
+        });
+        let singles = HISTORY_LEN - 2;
+        for n in 0..singles {
+            set_pitch(&mut state, 4, n % MAX_STEPS, n as u8 % 100 + 1);
+        }
+        let mut undone = 0;
+        while undo(&mut state) {
+            undone += 1;
+        }
+        assert_eq!(undone, singles);
+        // The dropped edit can't be undone any more, so it stays.
+        assert_eq!(pitch(&state, 0, 0), 50);
+        assert_eq!(pitch(&state, 4, 0), 0);
+    }
+
+    #[test]
+    fn undo_keeps_later_changes_to_other_settings() {
+        let mut state = SequencerState::new();
+        edit(&mut state, |pattern| {
+            pattern.tracks[0].lfo.shape = LfoShape::Square;
+            pattern.mutes.muted = 0b01;
+        });
+        // Not recorded, like a CC.
+        state.patterns[0].tracks[0].lfo.rate = 3;
+        state.patterns[0].mutes.muted |= 0b10;
+        assert!(undo(&mut state));
+        let lfo = state.patterns[0].tracks[0].lfo;
+        assert_eq!((lfo.shape, lfo.rate), (Lfo::new().shape, 3));
+        assert_eq!(state.patterns[0].mutes.muted, 0b10);
+    }
+
+    #[test]
+    fn global_mutes_are_undone() {
+        let mut state = SequencerState::new();
+        let before = Snapshot::take(&state);
+        state.mutes.soloed = 0b100;
+        record_changes(&mut state, &before);
+        assert!(undo(&mut state));
+        assert_eq!(state.mutes, Mutes::new());
+        assert!(redo(&mut state));
+        assert_eq!(state.mutes.soloed, 0b100);
+    }
+
+    #[test]
+    fn undo_in_the_edited_pattern_keeps_the_selection() {
+        let mut state = SequencerState::new();
+        set_pitch(&mut state, 0, 3, 60);
+        state.edit.steps = 0b1010;
+        assert!(undo(&mut state));
+        assert_eq!(state.edit.steps, 0b1010);
+    }
+}